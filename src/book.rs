@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use uuid::Uuid;
+
+use crate::source::Side;
+
+/// A price usable as a map key. Prices are validated as finite when
+/// parsed, so a total order over them is safe.
+#[derive(Clone, Copy, PartialEq)]
+struct Price(f64);
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+	fn partial_cmp(&self, other: &Price) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Price {
+	fn cmp(&self, other: &Price) -> Ordering {
+		self.0.total_cmp(&other.0)
+	}
+}
+
+struct Order {
+	side: Side,
+	price: Price,
+	size: f64,
+}
+
+/// Total resting size at a price and how many orders make it up. Levels
+/// are dropped by count rather than by size so float drift in the total
+/// can't leave an empty level behind.
+struct Level {
+	size: f64,
+	orders: usize,
+}
+
+/// Best bid and best ask, each as (price, total size at that price).
+pub type Top = (Option<(f64, f64)>, Option<(f64, f64)>);
+
+/// An order book rebuilt from individual order events.
+///
+/// It only knows about orders it has seen open, so until it is seeded from
+/// a full snapshot, orders resting from before the subscription are missing.
+pub struct Book {
+	orders: HashMap<Uuid, Order>,
+	bids: BTreeMap<Price, Level>,
+	asks: BTreeMap<Price, Level>,
+}
+
+impl Book {
+	pub fn new() -> Book {
+		Book {
+			orders: HashMap::new(),
+			bids: BTreeMap::new(),
+			asks: BTreeMap::new(),
+		}
+	}
+
+	pub fn open(&mut self, order_id: Uuid, side: Side, price: f64, size: f64) {
+		// An order can't open twice; treat a repeat as a replacement.
+		self.done(order_id);
+
+		let price = Price(price);
+		let level = self.side_mut(side).entry(price).or_insert(Level { size: 0.0, orders: 0 });
+		level.size += size;
+		level.orders += 1;
+
+		self.orders.insert(order_id, Order { side, price, size });
+	}
+
+	/// Takes `size` off a resting order that was matched against.
+	pub fn fill(&mut self, order_id: Uuid, size: f64) {
+		if let Some(order) = self.orders.get(&order_id) {
+			let remaining = (order.size - size).max(0.0);
+			self.resize(order_id, remaining);
+		}
+	}
+
+	/// Sets a resting order's remaining size.
+	pub fn resize(&mut self, order_id: Uuid, size: f64) {
+		let (side, price, old_size) = match self.orders.get_mut(&order_id) {
+			Some(order) => {
+				let old_size = order.size;
+				order.size = size;
+				(order.side, order.price, old_size)
+			}
+			None => return,
+		};
+
+		if let Some(level) = self.side_mut(side).get_mut(&price) {
+			level.size += size - old_size;
+		}
+	}
+
+	pub fn done(&mut self, order_id: Uuid) {
+		let order = match self.orders.remove(&order_id) {
+			Some(order) => order,
+			None => return,
+		};
+
+		let levels = self.side_mut(order.side);
+		if let Some(level) = levels.get_mut(&order.price) {
+			level.orders -= 1;
+			level.size -= order.size;
+
+			if level.orders == 0 {
+				levels.remove(&order.price);
+			}
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.orders.clear();
+		self.bids.clear();
+		self.asks.clear();
+	}
+
+	pub fn top(&self) -> Top {
+		(self.best_bid(), self.best_ask())
+	}
+
+	/// Best bid as (price, total size at that price).
+	pub fn best_bid(&self) -> Option<(f64, f64)> {
+		self.bids.iter().next_back().map(|(price, level)| (price.0, level.size))
+	}
+
+	/// Best ask as (price, total size at that price).
+	pub fn best_ask(&self) -> Option<(f64, f64)> {
+		self.asks.iter().next().map(|(price, level)| (price.0, level.size))
+	}
+
+	fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Price, Level> {
+		match side {
+			Side::Buy => &mut self.bids,
+			Side::Sell => &mut self.asks,
+		}
+	}
+}
+
+/// Books for every product, with the top of the book only looked at once a
+/// burst of updates has been applied rather than after each one.
+pub struct Books {
+	books: HashMap<String, Book>,
+	/// The top of the book at the last flush, for products updated since.
+	changed: HashMap<String, Top>,
+	updates: u64,
+	flushes: u64,
+}
+
+impl Books {
+	pub fn new() -> Books {
+		Books {
+			books: HashMap::new(),
+			changed: HashMap::new(),
+			updates: 0,
+			flushes: 0,
+		}
+	}
+
+	pub fn update(&mut self, product_id: &str, update: impl FnOnce(&mut Book)) {
+		let book = self.books.entry(product_id.to_string()).or_insert_with(Book::new);
+		self.changed.entry(product_id.to_string()).or_insert_with(|| book.top());
+		update(book);
+		self.updates += 1;
+	}
+
+	#[cfg(test)]
+	pub fn top(&self, product_id: &str) -> Option<Top> {
+		self.books.get(product_id).map(Book::top)
+	}
+
+	/// Takes a match off the maker's resting order. Products without a book,
+	/// as when only trades are subscribed, are left without one.
+	pub fn fill(&mut self, product_id: &str, maker_order_id: Uuid, size: f64) {
+		if self.books.contains_key(product_id) {
+			self.update(product_id, |book| book.fill(maker_order_id, size));
+		}
+	}
+
+	/// Empties a product's book, returning false if there was none.
+	pub fn clear(&mut self, product_id: &str) -> bool {
+		if !self.books.contains_key(product_id) {
+			return false;
+		}
+		self.update(product_id, Book::clear);
+		true
+	}
+
+	/// Returns the new top of the book for every product whose top moved
+	/// since the last flush.
+	pub fn flush(&mut self) -> Vec<(String, Top)> {
+		if self.changed.is_empty() {
+			return Vec::new();
+		}
+		self.flushes += 1;
+
+		let mut moved = Vec::new();
+		for (product_id, before) in self.changed.drain() {
+			let after = self.books[&product_id].top();
+			if after != before {
+				moved.push((product_id, after));
+			}
+		}
+		moved
+	}
+
+	/// Average number of updates applied per flush.
+	pub fn updates_per_flush(&self) -> Option<f64> {
+		if self.flushes == 0 {
+			None
+		} else {
+			Some(self.updates as f64 / self.flushes as f64)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn orders_at_a_price_share_a_level() {
+		let mut book = Book::new();
+		book.open(Uuid::from_u128(1), Side::Buy, 2450.00, 1.5);
+		book.open(Uuid::from_u128(2), Side::Buy, 2450.00, 0.5);
+		book.open(Uuid::from_u128(3), Side::Buy, 2449.00, 3.0);
+		book.open(Uuid::from_u128(4), Side::Sell, 2451.00, 2.0);
+
+		assert_eq!(book.top(), (Some((2450.00, 2.0)), Some((2451.00, 2.0))));
+	}
+
+	#[test]
+	fn fills_and_resizes_change_the_level_size() {
+		let mut book = Book::new();
+		book.open(Uuid::from_u128(1), Side::Sell, 2451.00, 2.0);
+		book.open(Uuid::from_u128(2), Side::Sell, 2451.00, 1.0);
+
+		book.fill(Uuid::from_u128(1), 0.5);
+		assert_eq!(book.best_ask(), Some((2451.00, 2.5)));
+
+		book.resize(Uuid::from_u128(2), 0.25);
+		assert_eq!(book.best_ask(), Some((2451.00, 1.75)));
+	}
+
+	#[test]
+	fn a_level_goes_when_its_last_order_is_done() {
+		let mut book = Book::new();
+		book.open(Uuid::from_u128(1), Side::Buy, 2450.00, 1.0);
+		book.open(Uuid::from_u128(2), Side::Buy, 2450.50, 0.5);
+
+		// Fully filled but not yet done, so still resting.
+		book.fill(Uuid::from_u128(2), 0.5);
+		assert_eq!(book.best_bid(), Some((2450.50, 0.0)));
+
+		book.done(Uuid::from_u128(2));
+		assert_eq!(book.best_bid(), Some((2450.00, 1.0)));
+
+		book.done(Uuid::from_u128(1));
+		assert_eq!(book.top(), (None, None));
+	}
+}
@@ -0,0 +1,629 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::diag;
+use crate::feed::Frame;
+use crate::heartbeat::{self, CoinbaseHeartbeat};
+use crate::latency::Latency;
+use crate::sanitize::sanitize;
+use crate::stats::MessageStats;
+use crate::source::{MarketDataSource, MarketEvent, Side};
+use crate::status::{self, CoinbaseStatus};
+use crate::subscription::{Confirmations, ProductId};
+
+/// Full channel messages. Each takes the next sequence number for its
+/// product, so unlike ticker messages they can't skip any. Matches only
+/// count on the full channel; the matches channel alone skips sequences.
+const ORDER_MESSAGES: &[&str] = &["received", "open", "done", "change", "activate", "match"];
+
+/// Messages replaying something from before we subscribed. Their time can
+/// be minutes or hours old, so it says nothing about latency.
+const HISTORY_MESSAGES: &[&str] = &["last_match"];
+
+#[derive(Deserialize)]
+struct CoinbaseEnvelope {
+	#[serde(alias = "type")]
+	message_type: String,
+	product_id: Option<String>,
+	sequence: Option<u64>,
+	time: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseSubscriptions {
+	channels: Vec<CoinbaseChannel>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseChannel {
+	name: String,
+	#[serde(default)]
+	product_ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseError {
+	message: String,
+	reason: Option<String>,
+}
+
+/// Sizes on the ticker are the size at the touch when the ticker fired, so
+/// they are a much rougher guide than the order flow.
+#[derive(Deserialize)]
+pub struct CoinbaseTicker {
+	#[serde(alias = "type")]
+	pub message_type: String,
+	pub product_id: String,
+	pub time: DateTime<Utc>,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	pub best_bid: f64,
+	#[serde(deserialize_with = "string_as_non_negative_f64")]
+	pub best_bid_size: f64,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	pub best_ask: f64,
+	#[serde(deserialize_with = "string_as_non_negative_f64")]
+	pub best_ask_size: f64,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseMatch {
+	product_id: String,
+	time: DateTime<Utc>,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	price: f64,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	size: f64,
+	side: Side,
+	maker_order_id: Uuid,
+}
+
+/// Market orders come with funds and no price.
+#[derive(Deserialize)]
+struct CoinbaseReceived {
+	order_id: Uuid,
+	#[serde(default, deserialize_with = "optional_string_as_positive_f64")]
+	price: Option<f64>,
+	side: Side,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseOpen {
+	product_id: String,
+	order_id: Uuid,
+	side: Side,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	price: f64,
+	#[serde(deserialize_with = "string_as_positive_f64")]
+	remaining_size: f64,
+}
+
+/// Market orders report a change in funds rather than size, but they never
+/// rest on the book, so only new_size matters here.
+#[derive(Deserialize)]
+struct CoinbaseChange {
+	product_id: String,
+	order_id: Uuid,
+	#[serde(default, deserialize_with = "optional_string_as_non_negative_f64")]
+	new_size: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseDone {
+	product_id: String,
+	order_id: Uuid,
+}
+
+/// Turns Coinbase feed frames, live or recorded, into market events.
+///
+/// Sequence checks, heartbeat gap detection and product status tracking
+/// are Coinbase specifics, so they happen here and only their outcomes are
+/// passed on as events. Gaps can only be detected on the full channel,
+/// which the source learns from the subscriptions confirmation.
+pub struct CoinbaseSource<I> {
+	frames: I,
+	sequences: heartbeat::Sequences,
+	statuses: status::ProductStatus,
+	full_channel: bool,
+	confirmations: Confirmations,
+	latency: Latency,
+	stats: MessageStats,
+	received: DateTime<Utc>,
+	backlog: bool,
+	pending: VecDeque<MarketEvent>,
+}
+
+impl<I: Iterator<Item = Frame>> CoinbaseSource<I> {
+	pub fn new(frames: I, products: &BTreeSet<ProductId>) -> CoinbaseSource<I> {
+		CoinbaseSource {
+			frames,
+			sequences: heartbeat::Sequences::new(products),
+			statuses: status::ProductStatus::new(products),
+			full_channel: false,
+			confirmations: Confirmations::new(products),
+			latency: Latency::new(),
+			stats: MessageStats::new(products),
+			received: Utc::now(),
+			backlog: false,
+			pending: VecDeque::new(),
+		}
+	}
+
+	fn process(&mut self, message: &str) {
+		//println!("{}", message);
+		let envelope: CoinbaseEnvelope = match parse(message) {
+			Some(v) => v,
+			None => return,
+		};
+
+		let product_id = envelope.product_id.as_deref();
+		self.stats.on_message(product_id, self.received);
+
+		if let Some(sent) = envelope.time {
+			if !HISTORY_MESSAGES.contains(&envelope.message_type.as_str()) {
+				self.latency.record(product_id, sent, self.received);
+			}
+		}
+
+		if envelope.message_type == "heartbeat" {
+			if let Some(v) = self.parse_for::<CoinbaseHeartbeat>(product_id, message) {
+				if let Some(missed) = self.sequences.on_heartbeat(&v, self.full_channel) {
+					self.pending.push_back(MarketEvent::Gap { product_id: v.product_id, missed });
+				}
+			}
+			return;
+		}
+
+		if let (Some(product_id), Some(sequence)) = (&envelope.product_id, envelope.sequence) {
+			// On the full channel order messages and matches are one
+			// contiguous stream. Anything else, like a ticker repeating the
+			// sequence of the match behind it, is only checked against its
+			// own kind.
+			let order_message = self.full_channel && ORDER_MESSAGES.contains(&envelope.message_type.as_str());
+			let stream = if order_message { heartbeat::FULL_STREAM } else { envelope.message_type.as_str() };
+
+			let last = self.sequences.last_sequence(product_id, stream);
+			if !self.sequences.on_message(product_id, stream, sequence) {
+				self.stats.on_out_of_order(product_id);
+				return;
+			}
+
+			if order_message {
+				if let Some(last) = last {
+					if sequence > last + 1 {
+						self.pending.push_back(MarketEvent::Gap {
+							product_id: product_id.clone(),
+							missed: sequence - last - 1,
+						});
+					}
+				}
+			}
+		}
+
+		match envelope.message_type.as_str() {
+			"subscriptions" => {
+				if let Some(v) = self.parse_for::<CoinbaseSubscriptions>(product_id, message) {
+					for channel in v.channels {
+						if channel.name == "full" {
+							self.full_channel = true;
+						}
+						self.confirmations.on_confirmed(&channel.name, &channel.product_ids);
+						self.pending.push_back(MarketEvent::Subscribed {
+							channel: channel.name,
+							product_ids: channel.product_ids,
+						});
+					}
+				}
+			}
+			"error" => {
+				if let Some(v) = self.parse_for::<CoinbaseError>(product_id, message) {
+					self.pending.push_back(MarketEvent::Error {
+						message: format!("{} ({})", v.message, v.reason.as_deref().unwrap_or("no reason given")),
+					});
+				}
+			}
+			"status" => {
+				if let Some(v) = self.parse_for::<CoinbaseStatus>(product_id, message) {
+					for (product_id, tradable, status) in self.statuses.on_status(&v) {
+						self.pending.push_back(MarketEvent::Tradable { product_id, tradable, status });
+					}
+				}
+			}
+			"ticker" => {
+				if let Some(v) = self.parse_for::<CoinbaseTicker>(product_id, message) {
+					self.pending.push_back(MarketEvent::Ticker {
+						product_id: v.product_id,
+						bid: v.best_bid,
+						bid_size: v.best_bid_size,
+						ask: v.best_ask,
+						ask_size: v.best_ask_size,
+					});
+				}
+			}
+			"match" | "last_match" => {
+				if let Some(v) = self.parse_for::<CoinbaseMatch>(product_id, message) {
+					self.pending.push_back(MarketEvent::Trade {
+						product_id: v.product_id,
+						time: v.time,
+						price: v.price,
+						size: v.size,
+						side: v.side,
+						maker_order_id: v.maker_order_id,
+					});
+				}
+			}
+			"received" => {
+				if let Some(v) = self.parse_for::<CoinbaseReceived>(product_id, message) {
+					self.pending.push_back(MarketEvent::OrderReceived {
+						order_id: v.order_id,
+						price: v.price,
+						side: v.side,
+					});
+				}
+			}
+			"open" => {
+				if let Some(v) = self.parse_for::<CoinbaseOpen>(product_id, message) {
+					self.pending.push_back(MarketEvent::OrderOpen {
+						product_id: v.product_id,
+						order_id: v.order_id,
+						side: v.side,
+						price: v.price,
+						size: v.remaining_size,
+					});
+				}
+			}
+			"change" => {
+				if let Some(v) = self.parse_for::<CoinbaseChange>(product_id, message) {
+					if let Some(size) = v.new_size {
+						self.pending.push_back(MarketEvent::OrderChange {
+							product_id: v.product_id,
+							order_id: v.order_id,
+							size,
+						});
+					}
+				}
+			}
+			"done" => {
+				if let Some(v) = self.parse_for::<CoinbaseDone>(product_id, message) {
+					self.pending.push_back(MarketEvent::OrderDone {
+						product_id: v.product_id,
+						order_id: v.order_id,
+					});
+				}
+			}
+			_ => {}
+		}
+	}
+
+	/// Pulls frames until one yields an event. Without `wait`, only frames
+	/// the feed already had waiting are pulled, so this never blocks.
+	fn pull(&mut self, wait: bool) -> Option<(DateTime<Utc>, MarketEvent)> {
+		loop {
+			if let Some(event) = self.pending.pop_front() {
+				return Some((self.received, event));
+			}
+
+			if !wait && !self.backlog {
+				return None;
+			}
+
+			let frame = self.frames.next()?;
+			self.received = frame.received;
+			self.backlog = frame.backlog;
+			self.process(&frame.text);
+			self.sequences.check_silent();
+			for warning in self.confirmations.check(self.received) {
+				println!("Warning: {}", warning);
+			}
+		}
+	}
+
+	/// Like `parse`, counting a failure against the product the message
+	/// is about.
+	fn parse_for<'a, T: Deserialize<'a>>(&mut self, product_id: Option<&str>, message: &'a str) -> Option<T> {
+		let parsed = parse(message);
+		if let (None, Some(product_id)) = (&parsed, product_id) {
+			self.stats.on_parse_failure(product_id);
+		}
+		parsed
+	}
+}
+
+impl<I: Iterator<Item = Frame>> MarketDataSource for CoinbaseSource<I> {
+	fn next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)> {
+		self.pull(true)
+	}
+
+	fn try_next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)> {
+		self.pull(false)
+	}
+
+	fn latency(&self) -> &Latency {
+		&self.latency
+	}
+
+	fn stats(&self) -> &MessageStats {
+		&self.stats
+	}
+}
+
+/// Deserializes a frame, warning (throttled) instead of failing when it
+/// doesn't match the expected shape.
+fn parse<'a, T: Deserialize<'a>>(message: &'a str) -> Option<T> {
+	match serde_json::from_str(message) {
+		Result::Ok(v) => Some(v),
+		Result::Err(e) => {
+			diag::warn_throttled("parse", Duration::from_secs(60), || sanitize(&e.to_string()));
+			None
+		}
+	}
+}
+
+fn string_as_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(F64Visitor)
+}
+
+fn string_as_positive_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = string_as_f64(deserializer)?;
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(de::Error::invalid_value(Unexpected::Float(value), &"a strictly positive f64"))
+    }
+}
+
+fn string_as_non_negative_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = string_as_f64(deserializer)?;
+    if value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(de::Error::invalid_value(Unexpected::Float(value), &"a non-negative f64"))
+    }
+}
+
+fn optional_string_as_positive_f64<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Positive(#[serde(deserialize_with = "string_as_positive_f64")] f64);
+
+    Ok(Option::<Positive>::deserialize(deserializer)?.map(|value| value.0))
+}
+
+fn optional_string_as_non_negative_f64<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct NonNegative(#[serde(deserialize_with = "string_as_non_negative_f64")] f64);
+
+    Ok(Option::<NonNegative>::deserialize(deserializer)?.map(|value| value.0))
+}
+
+struct F64Visitor;
+impl<'de> Visitor<'de> for F64Visitor {
+    type Value = f64;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string representation of a finite f64")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<f64, E>
+    where
+        E: de::Error,
+    {
+        // "inf" and "NaN" parse fine but are never a valid price or size
+        match value.parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(v),
+            _ => Err(E::invalid_value(Unexpected::Str(value), &"a string representation of a finite f64")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+	use crate::subscription;
+
+	/// Runs a fixture through a source watching `products` and collects
+	/// every event it produces.
+	fn events(fixture: &str, products: &[&str]) -> Vec<MarketEvent> {
+		let products = subscription::product_set(products).unwrap();
+		let mut source = CoinbaseSource::new(fixtures::frames(fixture).into_iter(), &products);
+
+		let mut events = Vec::new();
+		while let Some((_, event)) = source.next_event() {
+			events.push(event);
+		}
+		events
+	}
+
+	#[derive(Deserialize)]
+	struct Positive {
+		#[serde(deserialize_with = "string_as_positive_f64")]
+		value: f64,
+	}
+
+	fn positive(value: &str) -> Option<f64> {
+		let json = serde_json::json!({ "value": value }).to_string();
+		serde_json::from_str::<Positive>(&json).ok().map(|positive| positive.value)
+	}
+
+	#[test]
+	fn positive_accepts_a_price() {
+		assert_eq!(positive("2450.11"), Some(2450.11));
+	}
+
+	#[test]
+	fn positive_rejects_zero_negative_and_non_finite() {
+		for value in ["0", "-1", "inf", "NaN"] {
+			assert_eq!(positive(value), None, "{} was accepted", value);
+		}
+	}
+
+	#[test]
+	fn ticker_with_negative_size_is_dropped() {
+		let ticker = r#"{"type":"ticker","product_id":"ETH-USD","time":"2026-10-12T14:03:21.418734Z","best_bid":"2450.10","best_bid_size":"-1.2","best_ask":"2450.11","best_ask_size":"0.4"}"#;
+		assert!(parse::<CoinbaseTicker>(ticker).is_none());
+	}
+
+	#[test]
+	fn ticker_with_empty_side_is_kept() {
+		let ticker = r#"{"type":"ticker","product_id":"ETH-USD","time":"2026-10-12T14:03:21.418734Z","best_bid":"2450.10","best_bid_size":"0","best_ask":"2450.11","best_ask_size":"0.4"}"#;
+		assert!(parse::<CoinbaseTicker>(ticker).is_some());
+	}
+
+	#[test]
+	fn ticker_with_zero_ask_is_dropped() {
+		let ticker = r#"{"type":"ticker","product_id":"ETH-USD","time":"2026-10-12T14:03:21.418734Z","best_bid":"2450.10","best_bid_size":"1.2","best_ask":"0","best_ask_size":"0.4"}"#;
+		assert!(parse::<CoinbaseTicker>(ticker).is_none());
+	}
+
+	#[test]
+	fn change_with_negative_size_is_dropped() {
+		let change = r#"{"type":"change","product_id":"ETH-USD","order_id":"0f4c2a9e-6b1d-4e37-8a52-3c9d7e1f0a02","new_size":"-1.0"}"#;
+		assert!(parse::<CoinbaseChange>(change).is_none());
+	}
+
+	#[test]
+	fn change_without_size_is_kept() {
+		let change = r#"{"type":"change","product_id":"ETH-USD","order_id":"0f4c2a9e-6b1d-4e37-8a52-3c9d7e1f0a04","new_funds":"80.00"}"#;
+		assert_eq!(parse::<CoinbaseChange>(change).map(|change| change.new_size), Some(None));
+	}
+
+	#[test]
+	fn market_orders_are_received_without_a_price() {
+		let products = subscription::product_set(&["ETH-USD"]).unwrap();
+		let mut source = CoinbaseSource::new(fixtures::frames("full_eth_usd").into_iter(), &products);
+
+		let mut received = Vec::new();
+		while let Some((_, event)) = source.next_event() {
+			if let MarketEvent::OrderReceived { price, side, .. } = event {
+				received.push((price, side));
+			}
+		}
+
+		assert_eq!(received, [(Some(2450.00), Side::Buy), (Some(2451.00), Side::Sell), (None, Side::Buy)]);
+		assert!(source.stats().by_volume().iter().all(|(_, stats)| stats.parse_failures == 0));
+	}
+
+	#[test]
+	fn subscriptions_and_errors_become_events() {
+		let events = events("control", &["ETH-USD"]);
+
+		assert_eq!(events.len(), 4);
+		assert!(matches!(&events[0], MarketEvent::Subscribed { channel, product_ids } if channel == "full" && product_ids == &["ETH-USD"]));
+		assert!(matches!(&events[1], MarketEvent::Subscribed { channel, .. } if channel == "heartbeat"));
+		assert!(matches!(&events[2], MarketEvent::Subscribed { channel, product_ids } if channel == "status" && product_ids.is_empty()));
+		assert!(matches!(&events[3], MarketEvent::Error { message } if message == "Failed to subscribe (BTC-XYZ is not a valid product)"));
+	}
+
+	#[test]
+	fn heartbeats_ahead_of_the_ticker_are_not_gaps() {
+		let events = events("ticker", &["ETH-USD"]);
+
+		assert!(!events.iter().any(|event| matches!(event, MarketEvent::Gap { .. })));
+		assert_eq!(events.iter().filter(|event| matches!(event, MarketEvent::Ticker { .. })).count(), 2);
+	}
+
+	#[test]
+	fn matches_become_trades() {
+		let trades: Vec<(f64, f64, Side)> = events("matches", &["ETH-USD"]).into_iter()
+			.filter_map(|event| match event {
+				MarketEvent::Trade { price, size, side, .. } => Some((price, size, side)),
+				_ => None,
+			})
+			.collect();
+
+		assert_eq!(trades, [
+			(2450.11, 0.25, Side::Sell),
+			(2450.10, 0.0152, Side::Buy),
+			(2450.12, 1.5, Side::Sell),
+		]);
+	}
+
+	#[test]
+	fn matches_channel_sequence_skips_are_not_gaps() {
+		let events = events("matches", &["ETH-USD"]);
+
+		assert!(!events.iter().any(|event| matches!(event, MarketEvent::Gap { .. })));
+	}
+
+	#[test]
+	fn contiguous_full_channel_has_no_gaps() {
+		let events = events("full_eth_usd", &["ETH-USD"]);
+
+		assert!(!events.iter().any(|event| matches!(event, MarketEvent::Gap { .. })));
+	}
+
+	#[test]
+	fn missing_sequence_before_a_match_is_a_gap() {
+		let events = events("full_gap", &["ETH-USD"]);
+		let gap = events.iter().position(|event| matches!(event, MarketEvent::Gap { missed: 1, .. }));
+		let trade = events.iter().position(|event| matches!(event, MarketEvent::Trade { .. }));
+
+		assert!(gap.is_some(), "no gap reported");
+		assert_eq!(gap.map(|gap| gap + 1), trade);
+		assert_eq!(events.iter().filter(|event| matches!(event, MarketEvent::Gap { .. })).count(), 1);
+	}
+
+	#[test]
+	fn tickers_and_matches_sharing_a_sequence_both_come_through() {
+		let mut ticker = fixtures::frames("ticker").into_iter();
+		let mut matches = fixtures::frames("matches").into_iter();
+
+		// Subscriptions, then the last match, then each ticker right after
+		// the match that triggered it.
+		let frames = [
+			ticker.next(), matches.next(), matches.next(),
+			matches.next(), ticker.next(), ticker.next(),
+			matches.next(), ticker.next(), ticker.next(),
+		];
+		let frames: Vec<Frame> = frames.into_iter().map(Option::unwrap).collect();
+
+		let products = subscription::product_set(&["ETH-USD"]).unwrap();
+		let mut source = CoinbaseSource::new(frames.into_iter(), &products);
+
+		let (mut tickers, mut trades) = (0, 0);
+		while let Some((_, event)) = source.next_event() {
+			match event {
+				MarketEvent::Ticker { .. } => tickers += 1,
+				MarketEvent::Trade { .. } => trades += 1,
+				_ => {}
+			}
+		}
+
+		assert_eq!((tickers, trades), (2, 3));
+		assert!(source.stats().by_volume().iter().all(|(_, stats)| stats.out_of_order == 0));
+	}
+
+	#[test]
+	fn last_match_is_left_out_of_latency() {
+		let products = subscription::product_set(&["ETH-USD"]).unwrap();
+		let mut frames = fixtures::frames("matches");
+		let last_match = frames.remove(1);
+		let live_match = frames.remove(1);
+
+		let mut source = CoinbaseSource::new(vec![last_match].into_iter(), &products);
+		while source.next_event().is_some() {}
+		assert!(source.latency().overall().is_none());
+
+		let mut source = CoinbaseSource::new(vec![live_match].into_iter(), &products);
+		while source.next_event().is_some() {}
+		assert!(source.latency().overall().is_some());
+	}
+}
@@ -0,0 +1,155 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The registry is split so feed threads warning about different keys
+/// rarely wait on each other.
+const SHARDS: usize = 16;
+
+struct Throttle {
+	last_emit: Instant,
+	suppressed: u64,
+}
+
+struct Registry {
+	shards: Vec<Mutex<HashMap<String, Throttle>>>,
+}
+
+impl Registry {
+	fn new() -> Registry {
+		Registry {
+			shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+		}
+	}
+
+	fn shard(&self, key: &str) -> &Mutex<HashMap<String, Throttle>> {
+		let mut hasher = DefaultHasher::new();
+		key.hash(&mut hasher);
+		&self.shards[hasher.finish() as usize % SHARDS]
+	}
+
+	/// Decides whether a warning for `key` goes out at `now`. If it does,
+	/// returns how many were suppressed since the last one; otherwise counts
+	/// this one as suppressed and returns None.
+	fn check(&self, key: &str, interval: Duration, now: Instant) -> Option<u64> {
+		let mut throttles = self.shard(key).lock().unwrap();
+
+		match throttles.get_mut(key) {
+			Some(throttle) if now.duration_since(throttle.last_emit) < interval => {
+				throttle.suppressed += 1;
+				None
+			}
+			Some(throttle) => {
+				let suppressed = throttle.suppressed;
+				throttle.last_emit = now;
+				throttle.suppressed = 0;
+				Some(suppressed)
+			}
+			None => {
+				throttles.insert(key.to_string(), Throttle { last_emit: now, suppressed: 0 });
+				Some(0)
+			}
+		}
+	}
+
+	/// Takes the suppressed counts not yet reported, by key. The throttles
+	/// themselves stay as they are.
+	fn take_suppressed(&self) -> Vec<(String, u64)> {
+		let mut pending = Vec::new();
+
+		for shard in &self.shards {
+			for (key, throttle) in shard.lock().unwrap().iter_mut() {
+				if throttle.suppressed > 0 {
+					pending.push((key.clone(), throttle.suppressed));
+					throttle.suppressed = 0;
+				}
+			}
+		}
+
+		pending.sort();
+		pending
+	}
+}
+
+fn registry() -> &'static Registry {
+	static REGISTRY: OnceLock<Registry> = OnceLock::new();
+	REGISTRY.get_or_init(Registry::new)
+}
+
+/// Prints a warning at most once per `interval` for each `key`.
+///
+/// Warnings swallowed in between are counted, and the count is reported
+/// just before the next warning for that key goes out, or by
+/// `flush_suppressed`. `message_fn` is only called when the warning is
+/// actually printed.
+pub fn warn_throttled<F>(key: &str, interval: Duration, message_fn: F)
+where
+	F: FnOnce() -> String,
+{
+	let suppressed = match registry().check(key, interval, Instant::now()) {
+		Some(suppressed) => suppressed,
+		None => return,
+	};
+
+	if suppressed > 0 {
+		println!("[{}] ... suppressed {} similar warnings", key, suppressed);
+	}
+	println!("[{}] {}", key, message_fn());
+}
+
+/// Reports every suppressed count still pending, so a burst of warnings
+/// that stops is counted without waiting for another warning to come.
+pub fn flush_suppressed() {
+	for (key, suppressed) in registry().take_suppressed() {
+		println!("[{}] ... suppressed {} similar warnings", key, suppressed);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const INTERVAL: Duration = Duration::from_secs(60);
+
+	#[test]
+	fn warnings_within_the_interval_are_suppressed_and_counted() {
+		let registry = Registry::new();
+		let start = Instant::now();
+
+		assert_eq!(registry.check("parse", INTERVAL, start), Some(0));
+		assert_eq!(registry.check("parse", INTERVAL, start + Duration::from_secs(1)), None);
+		assert_eq!(registry.check("parse", INTERVAL, start + Duration::from_secs(2)), None);
+		assert_eq!(registry.check("parse", INTERVAL, start + INTERVAL), Some(2));
+		assert_eq!(registry.check("parse", INTERVAL, start + INTERVAL * 2), Some(0));
+	}
+
+	#[test]
+	fn keys_are_throttled_separately() {
+		let registry = Registry::new();
+		let start = Instant::now();
+
+		assert_eq!(registry.check("sequence ETH-USD", INTERVAL, start), Some(0));
+		assert_eq!(registry.check("sequence BTC-USD", INTERVAL, start), Some(0));
+		assert_eq!(registry.check("sequence ETH-USD", INTERVAL, start), None);
+	}
+
+	#[test]
+	fn pending_suppressed_counts_are_taken_once() {
+		let registry = Registry::new();
+		let start = Instant::now();
+
+		registry.check("binary", INTERVAL, start);
+		registry.check("binary", INTERVAL, start);
+		registry.check("binary", INTERVAL, start);
+		registry.check("replay", INTERVAL, start);
+
+		assert_eq!(registry.take_suppressed(), vec![("binary".to_string(), 2)]);
+		assert!(registry.take_suppressed().is_empty());
+
+		// Taking the count doesn't reopen the throttle early.
+		assert_eq!(registry.check("binary", INTERVAL, start + Duration::from_secs(1)), None);
+		assert_eq!(registry.check("binary", INTERVAL, start + INTERVAL), Some(1));
+	}
+}
@@ -0,0 +1,288 @@
+use std::collections::BTreeSet;
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use websocket::client::ClientBuilder;
+use websocket::stream::sync::{AsTcpStream, NetworkStream};
+use websocket::sync::Client;
+use websocket::{Message, OwnedMessage};
+
+use crate::diag;
+use crate::sanitize::sanitize;
+use crate::subscription::{self, ProductId};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(30);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A text frame and the local time it came off the socket.
+pub struct Frame {
+	pub received: DateTime<Utc>,
+	pub text: String,
+	/// Whether more frames were already waiting when this one was taken.
+	pub backlog: bool,
+}
+
+/// A websocket connection that reconnects and resubscribes on its own.
+///
+/// Any read or send error drops the connection. The next `recv` then
+/// reconnects with exponential backoff and sends the same subscribe frame.
+/// A connection that goes silent for `SILENCE_TIMEOUT` is treated as dead,
+/// since a half-open TCP connection would otherwise block reads forever.
+///
+/// The shutdown flag is checked between frames. With the heartbeat channel
+/// subscribed, a healthy connection delivers at least one frame a second.
+pub struct Feed {
+	name: String,
+	url: &'static str,
+	subscribe_message: String,
+	unsubscribe_message: String,
+	shutdown: Arc<AtomicBool>,
+	client: Option<Client<Box<dyn NetworkStream + Send>>>,
+	backoff: Duration,
+	reconnects: u64,
+	binary_frames: u64,
+	last_message: Instant,
+}
+
+impl Feed {
+	pub fn new(name: String, url: &'static str, subscribe_message: String, unsubscribe_message: String, shutdown: Arc<AtomicBool>) -> Feed {
+		Feed {
+			name,
+			url,
+			subscribe_message,
+			unsubscribe_message,
+			shutdown,
+			client: None,
+			backoff: INITIAL_BACKOFF,
+			reconnects: 0,
+			binary_frames: 0,
+			last_message: Instant::now(),
+		}
+	}
+
+	/// Blocks until the next text frame arrives, reconnecting as needed.
+	/// Returns None once a shutdown has been requested.
+	pub fn recv(&mut self) -> Option<String> {
+		loop {
+			if self.shutdown.load(Ordering::Relaxed) {
+				return None;
+			}
+
+			if self.client.is_none() {
+				// Gives up without a connection if a shutdown comes in meanwhile.
+				self.connect();
+				continue;
+			}
+
+			let result = self.client.as_mut().unwrap().recv_message();
+			match result {
+				Ok(message) => {
+					// Only a connection that actually delivers counts as healthy,
+					// otherwise a server that accepts and drops would be retried
+					// at the initial backoff forever.
+					self.backoff = INITIAL_BACKOFF;
+					self.last_message = Instant::now();
+
+					if let Some(text) = self.handle(message) {
+						return Some(text);
+					}
+				}
+				Err(e) => {
+					// The read timeout surfaces as an ordinary read error, so tell
+					// the two apart by how long it has been quiet.
+					let silence = self.last_message.elapsed();
+					if silence >= SILENCE_TIMEOUT {
+						self.disconnect(&format!("watchdog: no message for {:.1}s", silence.as_secs_f64()));
+					} else {
+						self.disconnect(&format!("read failed: {}", e));
+					}
+				}
+			}
+		}
+	}
+
+	/// Answers control frames, returning the payload of text frames.
+	fn handle(&mut self, message: OwnedMessage) -> Option<String> {
+		match message {
+			OwnedMessage::Text(text) => return Some(text),
+			OwnedMessage::Ping(data) => {
+				if let Err(e) = self.client.as_mut().unwrap().send_message(&OwnedMessage::Pong(data)) {
+					self.disconnect(&format!("pong failed: {}", e));
+				}
+			}
+			OwnedMessage::Pong(_) => {}
+			OwnedMessage::Close(data) => {
+				// Echo the close as the protocol expects; the socket is dropped
+				// either way, so a failure here doesn't matter.
+				let _ = self.client.as_mut().unwrap().send_message(&OwnedMessage::Close(None));
+
+				let reason = match data {
+					Some(data) => format!("server closed with {} {}", data.status_code, sanitize(&data.reason)),
+					None => "server closed".to_string(),
+				};
+				self.disconnect(&reason);
+			}
+			OwnedMessage::Binary(data) => {
+				self.binary_frames += 1;
+				let count = self.binary_frames;
+				diag::warn_throttled("binary", Duration::from_secs(60), || {
+					format!("ignoring binary frame of {} bytes, {} so far", data.len(), count)
+				});
+			}
+		}
+
+		None
+	}
+
+	fn connect(&mut self) {
+		while !self.shutdown.load(Ordering::Relaxed) {
+			println!("[{}] Connecting to {}", self.name, self.url);
+
+			match self.try_connect() {
+				Ok(client) => {
+					println!("[{}] Successfully connected", self.name);
+					self.client = Some(client);
+					self.last_message = Instant::now();
+					return;
+				}
+				Err(reason) => {
+					let delay = jitter(self.backoff);
+					println!("[{}] Connection failed ({}), retrying in {:.1}s", self.name, reason, delay.as_secs_f64());
+					thread::sleep(delay);
+					self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+				}
+			}
+		}
+	}
+
+	fn try_connect(&self) -> Result<Client<Box<dyn NetworkStream + Send>>, String> {
+		let mut client = ClientBuilder::new(self.url)
+			.unwrap()
+			.connect(None)
+			.map_err(|e| e.to_string())?;
+
+		client.stream_ref().as_tcp().set_read_timeout(Some(SILENCE_TIMEOUT))
+			.map_err(|e| e.to_string())?;
+
+		client.send_message(&Message::text(self.subscribe_message.as_str()))
+			.map_err(|e| e.to_string())?;
+
+		Ok(client)
+	}
+
+	/// Unsubscribes and closes the connection, waiting briefly for the
+	/// server to acknowledge the close.
+	pub fn close(&mut self) {
+		let mut client = match self.client.take() {
+			Some(client) => client,
+			None => return,
+		};
+
+		let result = client.send_message(&Message::text(self.unsubscribe_message.as_str()))
+			.and_then(|_| client.send_message(&OwnedMessage::Close(None)));
+		if let Err(e) = result {
+			println!("[{}] Could not close cleanly: {}", self.name, e);
+			return;
+		}
+
+		// Frames already in flight arrive ahead of the server's close.
+		let _ = client.stream_ref().as_tcp().set_read_timeout(Some(CLOSE_TIMEOUT));
+		let deadline = Instant::now() + CLOSE_TIMEOUT;
+		let mut acknowledged = false;
+		while !acknowledged && Instant::now() < deadline {
+			match client.recv_message() {
+				Ok(OwnedMessage::Close(_)) => acknowledged = true,
+				Ok(_) => {}
+				Err(_) => break,
+			}
+		}
+
+		if acknowledged {
+			println!("[{}] unsubscribed and closed connection", self.name);
+		} else {
+			println!("[{}] unsubscribed and closed connection (no close ack from server)", self.name);
+		}
+	}
+
+	fn disconnect(&mut self, reason: &str) {
+		self.client = None;
+		self.reconnects += 1;
+		println!("[{}] Disconnected ({}), reconnect #{}", self.name, reason, self.reconnects);
+	}
+}
+
+/// Splits `products` into shards of at most `shard_size`, each with its own
+/// connection on its own thread, and returns the text frames of all of them
+/// in the order they arrived.
+///
+/// Once `shutdown` is set, every shard closes its connection and the
+/// receiver runs dry.
+pub fn spawn_shards(url: &'static str, products: &BTreeSet<ProductId>, channels: &[&str], shard_size: usize, shutdown: &Arc<AtomicBool>) -> impl Iterator<Item = Frame> {
+	let (sender, receiver) = mpsc::channel();
+	let products: Vec<ProductId> = products.iter().cloned().collect();
+
+	for (index, chunk) in products.chunks(shard_size).enumerate() {
+		let name = format!("feed-{}", index);
+		let shard: BTreeSet<ProductId> = chunk.iter().cloned().collect();
+		let product_ids: Vec<&str> = shard.iter().map(ProductId::as_str).collect();
+		println!("[{}] {} products: {}", name, shard.len(), product_ids.join(", "));
+
+		let mut feed = Feed::new(
+			name.clone(),
+			url,
+			subscription::subscribe_message(&shard, channels),
+			subscription::unsubscribe_message(&shard, channels),
+			shutdown.clone(),
+		);
+		let sender = sender.clone();
+
+		thread::Builder::new()
+			.name(name)
+			.spawn(move || {
+				while let Some(text) = feed.recv() {
+					if sender.send(Frame { received: Utc::now(), text, backlog: false }).is_err() {
+						break;
+					}
+				}
+				feed.close();
+			})
+			.unwrap();
+	}
+
+	with_backlog(receiver)
+}
+
+/// Blocks for each frame as usual, but looks one frame ahead without
+/// blocking so each frame can say whether another is already waiting.
+fn with_backlog(receiver: Receiver<Frame>) -> impl Iterator<Item = Frame> {
+	let mut next: Option<Frame> = None;
+
+	iter::from_fn(move || {
+		let mut frame = match next.take() {
+			Some(frame) => frame,
+			None => receiver.recv().ok()?,
+		};
+		next = receiver.try_recv().ok();
+		frame.backlog = next.is_some();
+		Some(frame)
+	})
+}
+
+/// Picks a delay between half and all of `backoff` so clients that dropped
+/// together don't all come back at once.
+fn jitter(backoff: Duration) -> Duration {
+	let nanos = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.subsec_nanos() as u64;
+	let half = backoff / 2;
+
+	half + Duration::from_nanos(nanos % (half.as_nanos() as u64 + 1))
+}
@@ -0,0 +1,53 @@
+use std::fs;
+
+use crate::feed::Frame;
+use crate::replay;
+
+/// Every fixture under tests/fixtures. Each is a recording in the `--record`
+/// format, trimmed by hand and with order ids replaced:
+///
+/// - control: subscriptions confirming only ETH-USD, then an error frame
+/// - status: ETH-USD online, post-only, then online again
+/// - ticker: ticker and heartbeat frames, as `--ticker` receives them
+/// - matches: a last_match and two matches from the matches channel
+/// - full_eth_usd: a contiguous full-channel sequence, including a market
+///   order, a partial fill, a size change and a cancel
+/// - full_gap: full-channel frames with sequence 2002 missing
+///
+/// torn_recording, the matches fixture cut off partway through an extra
+/// last line, is left out as it doesn't load completely by design. Tests
+/// read it through `path`.
+pub const FIXTURES: &[&str] = &["control", "status", "ticker", "matches", "full_eth_usd", "full_gap"];
+
+pub fn path(name: &str) -> String {
+	format!("{}/tests/fixtures/{}.jsonl", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Loads a fixture through the replay reader. Unlike a replay, a line that
+/// doesn't load fails the test, so a fixture can't quietly go stale.
+pub fn frames(name: &str) -> Vec<Frame> {
+	let path = path(name);
+	let lines = fs::read_to_string(&path).unwrap().lines().count();
+	let frames: Vec<Frame> = replay::frames(&path).unwrap().collect();
+
+	assert_eq!(frames.len(), lines, "{} has lines that don't load", path);
+	frames
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn every_fixture_loads() {
+		for name in FIXTURES {
+			let frames = frames(name);
+			assert!(!frames.is_empty(), "{} is empty", name);
+
+			for frame in frames {
+				let message: serde_json::Value = serde_json::from_str(&frame.text).unwrap();
+				assert!(message["type"].is_string(), "{} has a frame without a type", name);
+			}
+		}
+	}
+}
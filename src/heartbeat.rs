@@ -0,0 +1,189 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::diag;
+use crate::subscription::ProductId;
+
+const SILENCE_WARNING: Duration = Duration::from_secs(30);
+
+/// The stream of full-channel order messages, the one heartbeats follow.
+pub const FULL_STREAM: &str = "full";
+
+#[derive(Deserialize)]
+pub struct CoinbaseHeartbeat {
+	pub product_id: String,
+	pub sequence: u64,
+}
+
+struct ProductSequence {
+	/// Last sequence seen on each stream. Streams share the product's
+	/// sequence numbers, so a ticker and the match behind it carry the
+	/// same one, and each is only checked against its own kind.
+	last_sequences: HashMap<String, u64>,
+	last_heartbeat: Option<Instant>,
+	dropped: u64,
+}
+
+/// Tracks per-product sequence numbers and heartbeats.
+///
+/// A heartbeat carries the sequence of the last message Coinbase sent for
+/// the product on the full channel. When we are subscribed to that channel
+/// and the heartbeat is ahead of the last message we actually saw, the
+/// messages in between were dropped. Other channels only see some of those
+/// sequence numbers, so there a heartbeat is always ahead.
+pub struct Sequences {
+	started: Instant,
+	products: HashMap<String, ProductSequence>,
+}
+
+impl Sequences {
+	pub fn new(products: &BTreeSet<ProductId>) -> Sequences {
+		Sequences {
+			started: Instant::now(),
+			products: products.iter()
+				.map(|product| (product.to_string(), ProductSequence {
+					last_sequences: HashMap::new(),
+					last_heartbeat: None,
+					dropped: 0,
+				}))
+				.collect(),
+		}
+	}
+
+	/// Records the sequence number carried by a regular channel message on
+	/// `stream`.
+	///
+	/// Returns false for a duplicate or for a message older than one already
+	/// seen on the same stream, which the caller should drop rather than apply.
+	pub fn on_message(&mut self, product_id: &str, stream: &str, sequence: u64) -> bool {
+		let product = match self.products.get_mut(product_id) {
+			Some(product) => product,
+			None => return true,
+		};
+
+		match product.last_sequences.get(stream) {
+			Some(&last) if sequence <= last => {
+				product.dropped += 1;
+				let dropped = product.dropped;
+				diag::warn_throttled(&format!("sequence {}", product_id), Duration::from_secs(60), || {
+					format!("dropped out-of-order {} message {} (already at {}), {} dropped so far", stream, sequence, last, dropped)
+				});
+				false
+			}
+			_ => {
+				product.last_sequences.insert(stream.to_string(), sequence);
+				true
+			}
+		}
+	}
+
+	/// The sequence of the last message seen for a product on `stream`.
+	pub fn last_sequence(&self, product_id: &str, stream: &str) -> Option<u64> {
+		self.products.get(product_id)?.last_sequences.get(stream).copied()
+	}
+
+	/// Records a heartbeat. With `full_channel`, also returns how many
+	/// messages were missed if it reveals a gap.
+	pub fn on_heartbeat(&mut self, heartbeat: &CoinbaseHeartbeat, full_channel: bool) -> Option<u64> {
+		let product = self.products.get_mut(&heartbeat.product_id)?;
+
+		product.last_heartbeat = Some(Instant::now());
+
+		if !full_channel {
+			return None;
+		}
+
+		let last = *product.last_sequences.get(FULL_STREAM)?;
+		if heartbeat.sequence > last {
+			product.last_sequences.insert(FULL_STREAM.to_string(), heartbeat.sequence);
+			Some(heartbeat.sequence - last)
+		} else {
+			None
+		}
+	}
+
+	/// Warns about subscribed products whose heartbeat has gone quiet, or
+	/// that never sent one at all.
+	pub fn check_silent(&self) {
+		let now = Instant::now();
+
+		for (product_id, product) in &self.products {
+			let silence = now.duration_since(product.last_heartbeat.unwrap_or(self.started));
+
+			if silence > SILENCE_WARNING {
+				diag::warn_throttled(&format!("heartbeat {}", product_id), SILENCE_WARNING, || {
+					format!("no heartbeat for {}s", silence.as_secs())
+				});
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::subscription;
+
+	fn sequences() -> Sequences {
+		Sequences::new(&subscription::product_set(&["ETH-USD"]).unwrap())
+	}
+
+	#[test]
+	fn duplicates_are_dropped() {
+		let mut sequences = sequences();
+
+		assert!(sequences.on_message("ETH-USD", FULL_STREAM, 1000));
+		assert!(!sequences.on_message("ETH-USD", FULL_STREAM, 1000));
+		assert!(sequences.on_message("ETH-USD", FULL_STREAM, 1001));
+	}
+
+	#[test]
+	fn a_reversed_pair_keeps_the_newer_message() {
+		let mut sequences = sequences();
+
+		assert!(sequences.on_message("ETH-USD", FULL_STREAM, 1002));
+		assert!(!sequences.on_message("ETH-USD", FULL_STREAM, 1001));
+		assert_eq!(sequences.last_sequence("ETH-USD", FULL_STREAM), Some(1002));
+		assert_eq!(sequences.products["ETH-USD"].dropped, 1);
+	}
+
+	#[test]
+	fn streams_are_checked_separately() {
+		let mut sequences = sequences();
+
+		assert!(sequences.on_message("ETH-USD", "match", 1001));
+		assert!(sequences.on_message("ETH-USD", "ticker", 1001));
+		assert!(!sequences.on_message("ETH-USD", "ticker", 1001));
+		assert_eq!(sequences.last_sequence("ETH-USD", FULL_STREAM), None);
+	}
+
+	#[test]
+	fn a_heartbeat_ahead_on_the_full_channel_is_a_gap() {
+		let mut sequences = sequences();
+		let heartbeat = CoinbaseHeartbeat { product_id: "ETH-USD".to_string(), sequence: 1005 };
+
+		sequences.on_message("ETH-USD", FULL_STREAM, 1002);
+		assert_eq!(sequences.on_heartbeat(&heartbeat, true), Some(3));
+		assert_eq!(sequences.on_heartbeat(&heartbeat, true), None);
+	}
+
+	#[test]
+	fn a_heartbeat_ahead_of_the_ticker_is_not_a_gap() {
+		let mut sequences = sequences();
+		let heartbeat = CoinbaseHeartbeat { product_id: "ETH-USD".to_string(), sequence: 1005 };
+
+		sequences.on_message("ETH-USD", FULL_STREAM, 1002);
+		assert_eq!(sequences.on_heartbeat(&heartbeat, false), None);
+		assert_eq!(sequences.last_sequence("ETH-USD", FULL_STREAM), Some(1002));
+	}
+
+	#[test]
+	fn unwatched_products_are_not_checked() {
+		let mut sequences = sequences();
+
+		assert!(sequences.on_message("BTC-USD", FULL_STREAM, 1002));
+		assert!(sequences.on_message("BTC-USD", FULL_STREAM, 1001));
+	}
+}
@@ -0,0 +1,84 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+
+const SAMPLES: usize = 1000;
+
+/// Latency over a window of recent messages, in milliseconds. Values can
+/// be negative when the local clock runs behind the exchange's.
+pub struct Summary {
+	pub min: i64,
+	pub median: i64,
+	pub p99: i64,
+}
+
+struct Samples(VecDeque<i64>);
+
+impl Samples {
+	fn new() -> Samples {
+		Samples(VecDeque::with_capacity(SAMPLES))
+	}
+
+	fn push(&mut self, latency_ms: i64) {
+		if self.0.len() == SAMPLES {
+			self.0.pop_front();
+		}
+		self.0.push_back(latency_ms);
+	}
+
+	fn summary(&self) -> Option<Summary> {
+		if self.0.is_empty() {
+			return None;
+		}
+
+		let mut sorted: Vec<i64> = self.0.iter().copied().collect();
+		sorted.sort_unstable();
+
+		Some(Summary {
+			min: sorted[0],
+			median: sorted[sorted.len() / 2],
+			p99: sorted[(sorted.len() * 99 / 100).min(sorted.len() - 1)],
+		})
+	}
+}
+
+/// Exchange-to-local latency over the last `SAMPLES` messages, overall and
+/// per product.
+pub struct Latency {
+	overall: Samples,
+	products: HashMap<String, Samples>,
+}
+
+impl Latency {
+	pub fn new() -> Latency {
+		Latency {
+			overall: Samples::new(),
+			products: HashMap::new(),
+		}
+	}
+
+	pub fn record(&mut self, product_id: Option<&str>, sent: DateTime<Utc>, received: DateTime<Utc>) {
+		let latency_ms = received.signed_duration_since(sent).num_milliseconds();
+
+		self.overall.push(latency_ms);
+		if let Some(product_id) = product_id {
+			self.products.entry(product_id.to_string())
+				.or_insert_with(Samples::new)
+				.push(latency_ms);
+		}
+	}
+
+	pub fn overall(&self) -> Option<Summary> {
+		self.overall.summary()
+	}
+
+	/// Per-product summaries, slowest p99 first.
+	pub fn products(&self) -> Vec<(&str, Summary)> {
+		let mut products: Vec<(&str, Summary)> = self.products.iter()
+			.filter_map(|(product_id, samples)| Some((product_id.as_str(), samples.summary()?)))
+			.collect();
+		products.sort_by_key(|(_, summary)| Reverse(summary.p99));
+		products
+	}
+}
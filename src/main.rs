@@ -1,98 +1,400 @@
 extern crate websocket;
 
-use websocket::client::ClientBuilder;
-use websocket::Message;
-use websocket::OwnedMessage;
-use serde::{Deserialize, Serialize};
-use serde::de::{self, Deserializer, Unexpected, Visitor};
-// use serde_json::{Result};
-use chrono::{DateTime, Utc, Timelike};
-use uuid::Uuid;
-use std::fmt;
-
-const CONNECTION: &'static str = "wss://ws-feed.exchange.coinbase.com";
+mod book;
+mod coinbase;
+mod diag;
+mod feed;
+#[cfg(test)]
+mod fixtures;
+mod heartbeat;
+mod latency;
+mod probe;
+mod record;
+mod replay;
+mod sanitize;
+mod source;
+mod stats;
+mod status;
+mod subscription;
+mod trades;
+
+use chrono::{DateTime, Utc};
+use sanitize::sanitize;
+use source::{MarketDataSource, MarketEvent};
+use subscription::ProductId;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECTION: &str = "wss://ws-feed.exchange.coinbase.com";
+const PRODUCT_IDS: &[&str] = &["ETH-USD"];
+const CHANNELS: &[&str] = &["full", "heartbeat", "status"];
+const TICKER_CHANNELS: &[&str] = &["ticker", "heartbeat", "status"];
+const SHARD_SIZE: usize = 50;
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 fn main() {
-	println!("Connecting to {}", CONNECTION);
+	let args: Vec<String> = std::env::args().collect();
 
-	let mut client = ClientBuilder::new(CONNECTION)
-		.unwrap()
-		.connect(None)
-		.unwrap();
+	if args.len() == 3 && args[1] == "probe" {
+		let healthy = probe::run(&args[2]);
+		std::process::exit(if healthy { 0 } else { 1 });
+	}
+
+	// Ticker mode trades the full order flow for one best bid/ask update per
+	// trade, which is far lighter on a weak connection.
+	let ticker_mode = args.iter().any(|arg| arg == "--ticker");
+	let mut channels = if ticker_mode { TICKER_CHANNELS } else { CHANNELS }.to_vec();
+
+	// The full channel already carries every match, so the matches channel
+	// only adds anything on top of the ticker.
+	if ticker_mode && args.iter().any(|arg| arg == "--matches") {
+		channels.push("matches");
+	}
+
+	let (record_path, replay_path) = match (flag_value(&args, "--record"), flag_value(&args, "--replay")) {
+		(Ok(record_path), Ok(replay_path)) => (record_path, replay_path),
+		(Err(e), _) | (_, Err(e)) => {
+			println!("{}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let recorder = match record_path {
+		Some(path) => match record::Recorder::create(path) {
+			Ok(recorder) => Some(recorder),
+			Err(e) => {
+				println!("Could not open {} for recording: {}", path, e);
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
 
-	println!("Successfully connected");
-
-	let start_message = Message::text(r#"{
-		"type": "subscribe",
-		"product_ids": [
-			"ETH-USD"
-		],
-		"channels": [
-			"full"
-		]
-	}"#);
-	
-	client.send_message(&start_message)
+	let shutdown = Arc::new(AtomicBool::new(false));
+	watch_stdin(shutdown.clone());
+
+	let (products, messages): (BTreeSet<ProductId>, Box<dyn Iterator<Item = feed::Frame>>) = match replay_path {
+		Some(path) => match replay::products(path).and_then(|products| Ok((products, replay::frames(path)?))) {
+			Ok((products, frames)) => {
+				println!("Replaying {} ({} products)", path, products.len());
+				let shutdown = shutdown.clone();
+				(products, Box::new(frames.take_while(move |_| !shutdown.load(Ordering::Relaxed))))
+			}
+			Err(e) => {
+				println!("Could not read {} for replay: {}", path, e);
+				std::process::exit(1);
+			}
+		},
+		None => {
+			let products = match subscription::product_set(PRODUCT_IDS) {
+				Ok(products) => products,
+				Err(invalid) => {
+					println!("Refusing to subscribe, invalid product ids: {}", invalid.join(", "));
+					std::process::exit(1);
+				}
+			};
+			let messages = feed::spawn_shards(CONNECTION, &products, &channels, SHARD_SIZE, &shutdown);
+			(products, Box::new(messages))
+		}
+	};
+
+	let frames = messages.inspect(|frame| {
+		if let Some(recorder) = &recorder {
+			recorder.record(frame);
+		}
+	});
+	let mut source = coinbase::CoinbaseSource::new(frames, &products);
+
+	run(&mut source, &mut trades::TradeFlow::new(), &mut book::Books::new());
+	diag::flush_suppressed();
+
+	// The source borrows the recorder, so it has to go first.
+	drop(source);
+	if let Some(recorder) = recorder {
+		recorder.close();
+	}
+}
+
+/// Requests a shutdown when "q" is entered on stdin. Live feeds then
+/// unsubscribe and close, which ends the stream of events like the end of a
+/// replay does.
+fn watch_stdin(shutdown: Arc<AtomicBool>) {
+	thread::Builder::new()
+		.name("stdin".to_string())
+		.spawn(move || {
+			let stdin = io::stdin();
+			for line in stdin.lock().lines() {
+				match line {
+					Ok(line) if line.trim() == "q" => {
+						println!("Shutting down");
+						shutdown.store(true, Ordering::Relaxed);
+						return;
+					}
+					Ok(_) => {}
+					Err(_) => return,
+				}
+			}
+		})
 		.unwrap();
+}
 
-	loop {
-		let incoming_message = client.recv_message()
-			.unwrap();
+/// Consumes market events until the source runs dry.
+fn run(source: &mut impl MarketDataSource, trades: &mut trades::TradeFlow, books: &mut book::Books) {
+	let mut last_report = Instant::now();
+	let mut last_total = 0;
 
-		process_owned_message(incoming_message);
+	while let Some((received, event)) = source.next_event() {
+		process_event(received, event, trades, books);
+
+		// Only the state at the end of a burst matters, so apply everything
+		// already waiting before looking at the books.
+		while let Some((received, event)) = source.try_next_event() {
+			process_event(received, event, trades, books);
+		}
+
+		for (product_id, top) in books.flush() {
+			if let (Some((bid, bid_size)), Some((ask, ask_size))) = top {
+				println!("[book] {} bid {} ({}) ask {} ({})", sanitize(&product_id), bid, bid_size, ask, ask_size);
+			}
+		}
+
+		if last_report.elapsed() >= REPORT_INTERVAL {
+			report_latency(source.latency());
+			diag::flush_suppressed();
+			if let Some(updates) = books.updates_per_flush() {
+				println!("book updates per evaluation: {:.1}", updates);
+			}
+
+			let total = source.stats().total();
+			report_stats(source.stats(), total - last_total, last_report.elapsed());
+			last_total = total;
+
+			last_report = Instant::now();
+		}
+	}
+}
+
+fn report_latency(latency: &latency::Latency) {
+	if let Some(overall) = latency.overall() {
+		println!("latency ms: min {} median {} p99 {}", overall.min, overall.median, overall.p99);
+	}
+
+	for (product_id, summary) in latency.products().iter().take(5) {
+		println!("  {} min {} median {} p99 {}", sanitize(product_id), summary.min, summary.median, summary.p99);
 	}
 }
 
-fn process_owned_message(message: OwnedMessage) {
-	match message {
-		OwnedMessage::Text(x) => process_coinbase_message(x),
-		OwnedMessage::Binary(_) => println!("binary"),
-		OwnedMessage::Close(_) => println!("close"),
-		OwnedMessage::Ping(_) => println!("ping"),
-		OwnedMessage::Pong(_) => println!("pong"),
+/// Prints the message rate since the last report, then the ten busiest and
+/// ten quietest products.
+fn report_stats(stats: &stats::MessageStats, messages: u64, elapsed: Duration) {
+	println!("messages: {:.1}/s", messages as f64 / elapsed.as_secs_f64());
+
+	let products = stats.by_volume();
+	println!("  busiest:");
+	for (product_id, product) in products.iter().take(10) {
+		report_product(product_id, product);
+	}
+
+	if products.len() > 10 {
+		println!("  quietest:");
+		for (product_id, product) in products.iter().rev().take(10) {
+			report_product(product_id, product);
+		}
 	}
 }
 
-#[derive(Serialize, Deserialize)]
-struct CoinbaseReceived {
-	order_id: Uuid,
-	#[serde(alias = "type")]
-	message_type: String,
-    time: DateTime<Utc>,
-	#[serde(deserialize_with = "string_as_f64")]
-	price: f64,
-	side: String,
+fn report_product(product_id: &str, product: &stats::ProductStats) {
+	let last_update = match product.last_update {
+		Some(time) => time.to_rfc3339(),
+		None => "never".to_string(),
+	};
+	println!("    {} {} messages, {} parse failures, {} out of order, last update {}",
+		sanitize(product_id), product.messages, product.parse_failures, product.out_of_order, last_update);
 }
 
-fn process_coinbase_message(message: String) {
-	//println!("{}", message);
-	let parsed_message: serde_json::Result<CoinbaseReceived> = serde_json::from_str(&message);
+fn process_event(received: DateTime<Utc>, event: MarketEvent, trades: &mut trades::TradeFlow, books: &mut book::Books) {
+	match event {
+		MarketEvent::Subscribed { channel, product_ids } => {
+			println!("Subscribed to {} for {}", sanitize(&channel), sanitize(&product_ids.join(", ")));
+		}
+		MarketEvent::Error { message } => {
+			println!("Exchange error: {}", sanitize(&message));
+		}
+		MarketEvent::Gap { product_id, missed } => {
+			println!("{} missed {} messages", sanitize(&product_id), missed);
 
-	match parsed_message {
-		Result::Ok(v) => println!("order_id {} type {} side {} price {}", v.order_id, v.message_type, v.side, v.price),
-		Result::Err(e) => println!("{}", e)
+			// With no snapshot to resync from, the book restarts empty and
+			// fills back in as orders open.
+			if books.clear(&product_id) {
+				println!("{} book cleared, rebuilding from new orders", sanitize(&product_id));
+			}
+		}
+		MarketEvent::Tradable { product_id, tradable, status } => {
+			if tradable {
+				println!("{} is tradable again ({})", sanitize(&product_id), sanitize(&status));
+			} else {
+				println!("{} is no longer tradable ({})", sanitize(&product_id), sanitize(&status));
+			}
+		}
+		MarketEvent::Ticker { product_id, bid, bid_size, ask, ask_size } => {
+			println!("[ticker] {} bid {} ({}) ask {} ({})", sanitize(&product_id), bid, bid_size, ask, ask_size);
+		}
+		MarketEvent::Trade { product_id, time, price, size, side, maker_order_id } => {
+			trades.on_trade(&product_id, time, price, size);
+			if let Some((volume, _)) = trades.recent(&product_id, received) {
+				println!("[match] {} {} {} @ {}, {} traded in the last 60s", sanitize(&product_id), side, size, price, volume);
+			}
+			books.fill(&product_id, maker_order_id, size);
+		}
+		MarketEvent::OrderReceived { order_id, price: Some(price), side } => {
+			println!("order_id {} received side {} price {}", order_id, side, price);
+		}
+		MarketEvent::OrderReceived { order_id, price: None, side } => {
+			println!("order_id {} received side {} price market", order_id, side);
+		}
+		MarketEvent::OrderOpen { product_id, order_id, side, price, size } => {
+			books.update(&product_id, |book| book.open(order_id, side, price, size));
+		}
+		MarketEvent::OrderChange { product_id, order_id, size } => {
+			books.update(&product_id, |book| book.resize(order_id, size));
+		}
+		MarketEvent::OrderDone { product_id, order_id } => {
+			books.update(&product_id, |book| book.done(order_id));
+		}
 	}
 }
 
-fn string_as_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    deserializer.deserialize_str(F64Visitor)
+/// Returns the value following `flag` on the command line, if the flag is
+/// present. A flag with nothing after it, or with another flag after it,
+/// is an error rather than absent, so a typo can't quietly turn it off.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, String> {
+	let index = match args.iter().position(|arg| arg == flag) {
+		Some(index) => index,
+		None => return Ok(None),
+	};
+
+	match args.get(index + 1) {
+		Some(value) if !value.starts_with("--") => Ok(Some(value)),
+		_ => Err(format!("{} needs a path", flag)),
+	}
 }
 
-struct F64Visitor;
-impl<'de> Visitor<'de> for F64Visitor {
-    type Value = f64;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string representation of a f64")
-    }
-    fn visit_str<E>(self, value: &str) -> Result<f64, E>
-    where
-        E: de::Error,
-    {
-        value.parse::<f64>().map_err(|_err| {
-            E::invalid_value(Unexpected::Str(value), &"a string representation of a f64")
-        })
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+	use crate::source::{MockSource, Side};
+	use uuid::Uuid;
+
+	fn open(order_id: u128, side: Side, price: f64, size: f64) -> MarketEvent {
+		MarketEvent::OrderOpen { product_id: "ETH-USD".to_string(), order_id: Uuid::from_u128(order_id), side, price, size }
+	}
+
+	fn trade(maker_order_id: u128, side: Side, price: f64, size: f64) -> MarketEvent {
+		MarketEvent::Trade {
+			product_id: "ETH-USD".to_string(),
+			time: Utc::now(),
+			price,
+			size,
+			side,
+			maker_order_id: Uuid::from_u128(maker_order_id),
+		}
+	}
+
+	#[test]
+	fn updates_from_a_source_reach_the_book_and_trade_flow() {
+		let mut source = MockSource::new(vec![
+			vec![open(1, Side::Buy, 2450.00, 1.5)],
+			vec![open(2, Side::Sell, 2451.00, 2.0)],
+			vec![trade(2, Side::Sell, 2451.00, 0.5)],
+		]);
+		let mut trades = trades::TradeFlow::new();
+		let mut books = book::Books::new();
+
+		run(&mut source, &mut trades, &mut books);
+
+		assert_eq!(books.top("ETH-USD"), Some((Some((2450.00, 1.5)), Some((2451.00, 1.5)))));
+		assert_eq!(trades.recent("ETH-USD", Utc::now()), Some((0.5, 2451.00)));
+		assert_eq!(books.updates_per_flush(), Some(1.0));
+	}
+
+	#[test]
+	fn a_burst_is_applied_before_the_book_is_checked() {
+		let mut source = MockSource::new(vec![
+			vec![
+				open(1, Side::Buy, 2450.00, 1.5),
+				open(2, Side::Sell, 2451.00, 2.0),
+				open(3, Side::Buy, 2450.50, 0.5),
+			],
+			vec![open(4, Side::Sell, 2450.90, 0.1)],
+		]);
+		let mut trades = trades::TradeFlow::new();
+		let mut books = book::Books::new();
+
+		run(&mut source, &mut trades, &mut books);
+
+		assert_eq!(books.top("ETH-USD"), Some((Some((2450.50, 0.5)), Some((2450.90, 0.1)))));
+		assert_eq!(books.updates_per_flush(), Some(2.0));
+	}
+
+	#[test]
+	fn trades_alone_leave_no_book() {
+		let mut source = MockSource::new(vec![
+			vec![trade(1, Side::Buy, 2450.10, 0.0152)],
+			vec![trade(2, Side::Sell, 2450.12, 1.5)],
+		]);
+		let mut trades = trades::TradeFlow::new();
+		let mut books = book::Books::new();
+
+		run(&mut source, &mut trades, &mut books);
+
+		assert_eq!(books.top("ETH-USD"), None);
+		assert_eq!(books.updates_per_flush(), None);
+	}
+
+	fn args(args: &[&str]) -> Vec<String> {
+		args.iter().map(|arg| arg.to_string()).collect()
+	}
+
+	#[test]
+	fn flag_values_follow_their_flag() {
+		let args = args(&["arbit", "--ticker", "--record", "session.jsonl"]);
+
+		assert_eq!(flag_value(&args, "--record"), Ok(Some("session.jsonl")));
+		assert_eq!(flag_value(&args, "--replay"), Ok(None));
+	}
+
+	#[test]
+	fn a_flag_without_its_value_is_an_error() {
+		assert!(flag_value(&args(&["arbit", "--record"]), "--record").is_err());
+		assert!(flag_value(&args(&["arbit", "--replay", "--ticker"]), "--replay").is_err());
+	}
+
+	/// Replays a fixture through a Coinbase source into fresh books.
+	fn replay(fixture: &str) -> book::Books {
+		let products = subscription::product_set(&["ETH-USD"]).unwrap();
+		let mut source = coinbase::CoinbaseSource::new(fixtures::frames(fixture).into_iter(), &products);
+		let mut books = book::Books::new();
+
+		run(&mut source, &mut trades::TradeFlow::new(), &mut books);
+		books
+	}
+
+	#[test]
+	fn full_channel_replay_rebuilds_the_book() {
+		let books = replay("full_eth_usd");
+
+		assert_eq!(books.top("ETH-USD"), Some((Some((2450.00, 1.5)), Some((2451.00, 1.0)))));
+	}
+
+	#[test]
+	fn a_gap_clears_the_book_before_it_is_rebuilt() {
+		let books = replay("full_gap");
+
+		assert_eq!(books.top("ETH-USD"), Some((Some((2441.00, 0.7)), None)));
+	}
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use websocket::client::ClientBuilder;
+use websocket::stream::sync::AsTcpStream;
+use websocket::{Message, OwnedMessage};
+
+use crate::sanitize::sanitize;
+use crate::subscription::{self, ProductId};
+use crate::coinbase::CoinbaseTicker;
+use crate::CONNECTION;
+
+const PROBE_DURATION: Duration = Duration::from_secs(10);
+
+/// Opens a short-lived connection of its own, subscribes to the ticker for
+/// a single product and reports what arrives within `PROBE_DURATION`.
+///
+/// Returns true if the product looks healthy upstream.
+pub fn run(product_id: &str) -> bool {
+	let product = match ProductId::parse(product_id) {
+		Some(product) => product,
+		None => {
+			println!("Invalid product id: {}", sanitize(product_id));
+			return false;
+		}
+	};
+
+	println!("Probing {} via {}", product, CONNECTION);
+
+	let mut client = match ClientBuilder::new(CONNECTION).unwrap().connect(None) {
+		Ok(client) => client,
+		Err(e) => {
+			println!("verdict: dead-upstream (could not connect: {})", e);
+			return false;
+		}
+	};
+
+	let subscribe = subscription::subscribe_message(&BTreeSet::from([product]), &["ticker"]);
+	if let Err(e) = client.send_message(&Message::text(subscribe)) {
+		println!("verdict: dead-upstream (could not subscribe: {})", e);
+		return false;
+	}
+
+	let started = Instant::now();
+	let deadline = started + PROBE_DURATION;
+	let mut tickers = 0u64;
+	let mut others = 0u64;
+	let mut top_of_book = None;
+	let mut latencies_ms = Vec::new();
+
+	loop {
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if remaining.is_zero() {
+			break;
+		}
+		// A read timeout only ever fires at the deadline, so it can't cut a
+		// frame in half while the probe still cares about the stream.
+		if let Err(e) = client.stream_ref().as_tcp().set_read_timeout(Some(remaining)) {
+			println!("verdict: dead-upstream (could not set read timeout: {})", e);
+			return false;
+		}
+
+		match client.recv_message() {
+			Ok(OwnedMessage::Text(text)) => match serde_json::from_str::<CoinbaseTicker>(&text) {
+				Ok(ticker) if ticker.message_type == "ticker" => {
+					tickers += 1;
+					top_of_book = Some((ticker.best_bid, ticker.best_ask));
+					latencies_ms.push(Utc::now().signed_duration_since(ticker.time).num_milliseconds());
+				}
+				_ => others += 1,
+			},
+			Ok(OwnedMessage::Ping(data)) => {
+				if let Err(e) = client.send_message(&OwnedMessage::Pong(data)) {
+					println!("verdict: dead-upstream (could not answer ping: {})", e);
+					return false;
+				}
+			}
+			Ok(OwnedMessage::Close(_)) => {
+				println!("server closed the probe connection");
+				break;
+			}
+			Ok(_) => others += 1,
+			Err(e) => {
+				if Instant::now() < deadline {
+					println!("probe read failed: {}", e);
+				}
+				break;
+			}
+		}
+	}
+
+	println!("{} ticker messages, {} other messages in {:.1}s", tickers, others, started.elapsed().as_secs_f64());
+
+	if let Some((bid, ask)) = top_of_book {
+		println!("top of book: bid {} ask {}", bid, ask);
+	}
+
+	if !latencies_ms.is_empty() {
+		latencies_ms.sort_unstable();
+		println!("latency ms: min {} median {} max {}",
+			latencies_ms[0],
+			latencies_ms[latencies_ms.len() / 2],
+			latencies_ms[latencies_ms.len() - 1]);
+	}
+
+	if tickers > 0 {
+		println!("verdict: healthy");
+		true
+	} else {
+		println!("verdict: dead-upstream");
+		false
+	}
+}
@@ -0,0 +1,82 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use chrono::{DateTime, Utc};
+
+use crate::feed::Frame;
+
+/// Appends every received text frame to a JSONL file as
+/// `{"ts": <local receive time>, "msg": <raw frame>}`.
+///
+/// Serialization and disk writes happen on a background thread so the
+/// receive loop never waits on the file.
+pub struct Recorder {
+	sender: Sender<(DateTime<Utc>, String)>,
+	writer: JoinHandle<()>,
+}
+
+impl Recorder {
+	pub fn create(path: &str) -> io::Result<Recorder> {
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)?;
+		let (sender, receiver) = mpsc::channel();
+
+		let writer = thread::Builder::new()
+			.name("recorder".to_string())
+			.spawn(move || write_frames(BufWriter::new(file), receiver))?;
+
+		println!("Recording to {}", path);
+
+		Ok(Recorder { sender, writer })
+	}
+
+	pub fn record(&self, frame: &Frame) {
+		// The writer only goes away after a write error, which it has
+		// already reported.
+		let _ = self.sender.send((frame.received, frame.text.clone()));
+	}
+
+	/// Writes out everything still queued, syncs the file and waits for the
+	/// writer to finish.
+	pub fn close(self) {
+		drop(self.sender);
+		let _ = self.writer.join();
+	}
+}
+
+fn write_frames(mut out: BufWriter<File>, frames: Receiver<(DateTime<Utc>, String)>) {
+	let mut messages = 0u64;
+	let mut bytes = 0u64;
+
+	// Block for the next frame, then write whatever else is already queued
+	// and flush. An abrupt exit loses at most the frames still in flight.
+	'outer: while let Ok(first) = frames.recv() {
+		for (received, text) in std::iter::once(first).chain(frames.try_iter()) {
+			let line = serde_json::json!({
+				"ts": received.to_rfc3339(),
+				"msg": text,
+			}).to_string();
+
+			if let Err(e) = writeln!(out, "{}", line) {
+				println!("Recorder write failed, stopping: {}", e);
+				break 'outer;
+			}
+			messages += 1;
+			bytes += line.len() as u64 + 1;
+		}
+
+		if let Err(e) = out.flush() {
+			println!("Recorder flush failed, stopping: {}", e);
+			break;
+		}
+	}
+
+	if let Err(e) = out.flush().and_then(|_| out.get_ref().sync_all()) {
+		println!("Recorder failed to sync: {}", e);
+	}
+	println!("Recorder closed, {} messages and {} bytes written", messages, bytes);
+}
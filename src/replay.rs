@@ -0,0 +1,98 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::diag;
+use crate::feed::Frame;
+use crate::subscription::ProductId;
+
+#[derive(Deserialize)]
+struct RecordedFrame {
+	ts: DateTime<Utc>,
+	msg: String,
+}
+
+#[derive(Deserialize)]
+struct ProductField {
+	product_id: Option<String>,
+}
+
+/// Collects the product ids that appear in a recording, so a replay
+/// watches the same products the recording session did.
+pub fn products(path: &str) -> io::Result<BTreeSet<ProductId>> {
+	let mut products = BTreeSet::new();
+
+	for frame in frames(path)? {
+		if let Ok(ProductField { product_id: Some(id) }) = serde_json::from_str(&frame.text) {
+			if let Some(product) = ProductId::parse(&id) {
+				products.insert(product);
+			}
+		}
+	}
+
+	Ok(products)
+}
+
+/// Streams the frames of a recording made with `--record`, in order and
+/// with their original receive times.
+///
+/// Lines that don't parse are skipped with a warning, so the torn last line
+/// of a recording that was cut short is harmless. Frames never report a
+/// backlog, so a replay is processed like a live feed at a trickle.
+pub fn frames(path: &str) -> io::Result<impl Iterator<Item = Frame>> {
+	let reader = BufReader::new(File::open(path)?);
+
+	Ok(reader.lines()
+		.map_while(|line| match line {
+			Ok(line) => Some(line),
+			Err(e) => {
+				println!("Replay stopped, read failed: {}", e);
+				None
+			}
+		})
+		.enumerate()
+		.filter_map(|(index, line)| match serde_json::from_str::<RecordedFrame>(&line) {
+			Ok(recorded) => Some(Frame { received: recorded.ts, text: recorded.msg, backlog: false }),
+			Err(e) => {
+				diag::warn_throttled("replay", Duration::from_secs(60), || format!("skipping line {}: {}", index + 1, e));
+				None
+			}
+		}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+
+	#[test]
+	fn a_torn_last_line_is_skipped() {
+		let frames: Vec<Frame> = frames(&fixtures::path("torn_recording")).unwrap().collect();
+		let complete = fixtures::frames("matches");
+
+		assert_eq!(frames.len(), complete.len());
+		for (frame, expected) in frames.iter().zip(&complete) {
+			assert_eq!(frame.received, expected.received);
+			assert_eq!(frame.text, expected.text);
+			assert!(!frame.backlog);
+		}
+	}
+
+	#[test]
+	fn products_come_from_the_recording() {
+		let products = products(&fixtures::path("torn_recording")).unwrap();
+		let ids: Vec<&str> = products.iter().map(ProductId::as_str).collect();
+
+		assert_eq!(ids, ["ETH-USD"]);
+	}
+
+	#[test]
+	fn a_missing_recording_is_an_error() {
+		assert!(frames(&fixtures::path("no_such_recording")).is_err());
+		assert!(products(&fixtures::path("no_such_recording")).is_err());
+	}
+}
@@ -0,0 +1,58 @@
+const MAX_CHARS: usize = 256;
+
+/// Makes a network-derived string safe to print to the terminal.
+///
+/// Control characters (including ESC, so ANSI sequences are inert) are
+/// replaced with their escaped form and the result is capped at
+/// `MAX_CHARS` characters. Anything that came off the wire should go
+/// through this before it reaches stdout.
+pub fn sanitize(value: &str) -> String {
+	let mut out = String::with_capacity(value.len().min(MAX_CHARS));
+
+	for (count, c) in value.chars().enumerate() {
+		if count == MAX_CHARS {
+			out.push_str("...");
+			break;
+		}
+		if c.is_control() {
+			out.extend(c.escape_default());
+		} else {
+			out.push(c);
+		}
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ansi_escapes_are_made_inert() {
+		let reason = "\u{1b}[2J\u{1b}[31mBTC-USD\u{1b}[0m halted";
+		let sanitized = sanitize(reason);
+
+		assert!(!sanitized.contains('\u{1b}'));
+		assert_eq!(sanitized, "\\u{1b}[2J\\u{1b}[31mBTC-USD\\u{1b}[0m halted");
+	}
+
+	#[test]
+	fn other_control_characters_are_escaped() {
+		assert_eq!(sanitize("line\nbreak\r\u{7}"), "line\\nbreak\\r\\u{7}");
+	}
+
+	#[test]
+	fn printable_text_is_unchanged() {
+		assert_eq!(sanitize("ETH-USD is in cancel-only mode (état spécial)"), "ETH-USD is in cancel-only mode (état spécial)");
+	}
+
+	#[test]
+	fn long_values_are_capped() {
+		let sanitized = sanitize(&"A".repeat(MAX_CHARS * 4));
+
+		assert_eq!(sanitized.chars().count(), MAX_CHARS + 3);
+		assert!(sanitized.ends_with("..."));
+		assert_eq!(sanitize(&"A".repeat(MAX_CHARS)), "A".repeat(MAX_CHARS));
+	}
+}
@@ -0,0 +1,116 @@
+#[cfg(test)]
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::latency::Latency;
+use crate::stats::MessageStats;
+
+/// What happened in the market, independent of which exchange said so.
+///
+/// Strings are exactly as the exchange sent them; sanitize before printing.
+pub enum MarketEvent {
+	/// The exchange confirmed a subscription to a channel.
+	Subscribed { channel: String, product_ids: Vec<String> },
+	/// The exchange reported a problem with our connection or request.
+	Error { message: String },
+	/// Messages for a product were lost, so anything built from its earlier
+	/// messages can't be trusted.
+	Gap { product_id: String, missed: u64 },
+	/// A product started or stopped accepting taker orders.
+	Tradable { product_id: String, tradable: bool, status: String },
+	/// The best bid and ask as reported by the exchange's ticker.
+	Ticker { product_id: String, bid: f64, bid_size: f64, ask: f64, ask_size: f64 },
+	/// An executed trade. The side is the resting (maker) order's.
+	Trade { product_id: String, time: DateTime<Utc>, price: f64, size: f64, side: Side, maker_order_id: Uuid },
+	/// A new order reached the exchange's matching engine. Market orders
+	/// have no price.
+	OrderReceived { order_id: Uuid, price: Option<f64>, side: Side },
+	/// An order started resting on the book.
+	OrderOpen { product_id: String, order_id: Uuid, side: Side, price: f64, size: f64 },
+	/// A resting order's remaining size was changed in place.
+	OrderChange { product_id: String, order_id: Uuid, size: f64 },
+	/// An order left the book, whether filled or cancelled.
+	OrderDone { product_id: String, order_id: Uuid },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+	Buy,
+	Sell,
+}
+
+impl fmt::Display for Side {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Side::Buy => f.write_str("buy"),
+			Side::Sell => f.write_str("sell"),
+		}
+	}
+}
+
+/// Anything that can feed the pipeline, such as a live exchange connection
+/// or a recording of one.
+pub trait MarketDataSource {
+	/// Returns the next event with the local time its message was received,
+	/// or None once the source is exhausted.
+	fn next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)>;
+
+	/// Returns the next event only if it is ready without waiting, so a
+	/// burst can be drained before acting on the state it leaves behind.
+	fn try_next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)>;
+
+	/// How long messages took to get from the exchange to us.
+	fn latency(&self) -> &Latency;
+
+	/// How many messages each product has sent.
+	fn stats(&self) -> &MessageStats;
+}
+
+/// Hands out prepared events, for tests. Each batch arrives as a burst:
+/// its first event as if after a wait, the rest as already waiting.
+#[cfg(test)]
+pub struct MockSource {
+	batches: VecDeque<VecDeque<MarketEvent>>,
+	ready: VecDeque<MarketEvent>,
+	latency: Latency,
+	stats: MessageStats,
+}
+
+#[cfg(test)]
+impl MockSource {
+	pub fn new(batches: Vec<Vec<MarketEvent>>) -> MockSource {
+		MockSource {
+			batches: batches.into_iter().map(VecDeque::from).collect(),
+			ready: VecDeque::new(),
+			latency: Latency::new(),
+			stats: MessageStats::new(&BTreeSet::new()),
+		}
+	}
+}
+
+#[cfg(test)]
+impl MarketDataSource for MockSource {
+	fn next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)> {
+		while self.ready.is_empty() {
+			self.ready = self.batches.pop_front()?;
+		}
+		self.try_next_event()
+	}
+
+	fn try_next_event(&mut self) -> Option<(DateTime<Utc>, MarketEvent)> {
+		self.ready.pop_front().map(|event| (Utc::now(), event))
+	}
+
+	fn latency(&self) -> &Latency {
+		&self.latency
+	}
+
+	fn stats(&self) -> &MessageStats {
+		&self.stats
+	}
+}
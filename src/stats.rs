@@ -0,0 +1,93 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+
+use crate::subscription::ProductId;
+
+#[derive(Default)]
+pub struct ProductStats {
+	pub messages: u64,
+	pub parse_failures: u64,
+	/// Duplicate or older messages dropped by the sequence check.
+	pub out_of_order: u64,
+	pub last_update: Option<DateTime<Utc>>,
+}
+
+/// Message counts per product, to show which products drive the volume and
+/// which have gone quiet.
+pub struct MessageStats {
+	total: u64,
+	products: HashMap<String, ProductStats>,
+}
+
+impl MessageStats {
+	/// Subscribed products start at zero so ones that never send anything
+	/// still show up as the quietest.
+	pub fn new(products: &BTreeSet<ProductId>) -> MessageStats {
+		MessageStats {
+			total: 0,
+			products: products.iter()
+				.map(|product| (product.to_string(), ProductStats::default()))
+				.collect(),
+		}
+	}
+
+	pub fn on_message(&mut self, product_id: Option<&str>, received: DateTime<Utc>) {
+		self.total += 1;
+
+		if let Some(product_id) = product_id {
+			let product = self.products.entry(product_id.to_string()).or_default();
+			product.messages += 1;
+			product.last_update = Some(received);
+		}
+	}
+
+	pub fn on_parse_failure(&mut self, product_id: &str) {
+		self.products.entry(product_id.to_string()).or_default().parse_failures += 1;
+	}
+
+	pub fn on_out_of_order(&mut self, product_id: &str) {
+		self.products.entry(product_id.to_string()).or_default().out_of_order += 1;
+	}
+
+	/// All messages, including those not about any one product.
+	pub fn total(&self) -> u64 {
+		self.total
+	}
+
+	/// Products from most to fewest messages.
+	pub fn by_volume(&self) -> Vec<(&str, &ProductStats)> {
+		let mut products: Vec<(&str, &ProductStats)> = self.products.iter()
+			.map(|(product_id, stats)| (product_id.as_str(), stats))
+			.collect();
+		products.sort_by(|a, b| b.1.messages.cmp(&a.1.messages).then(a.0.cmp(b.0)));
+		products
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::subscription;
+
+	#[test]
+	fn silent_products_are_listed_last() {
+		let products = subscription::product_set(&["BTC-USD", "ETH-USD"]).unwrap();
+		let mut stats = MessageStats::new(&products);
+		let now = Utc::now();
+
+		stats.on_message(Some("ETH-USD"), now);
+		stats.on_message(Some("ETH-USD"), now);
+		stats.on_message(None, now);
+		stats.on_out_of_order("ETH-USD");
+
+		let by_volume = stats.by_volume();
+		assert_eq!(stats.total(), 3);
+		assert_eq!(by_volume[0].0, "ETH-USD");
+		assert_eq!(by_volume[0].1.messages, 2);
+		assert_eq!(by_volume[0].1.out_of_order, 1);
+		assert_eq!(by_volume[1].0, "BTC-USD");
+		assert_eq!(by_volume[1].1.messages, 0);
+		assert!(by_volume[1].1.last_update.is_none());
+	}
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Deserialize;
+
+use crate::subscription::ProductId;
+
+#[derive(Deserialize)]
+pub struct CoinbaseStatus {
+	products: Vec<CoinbaseProductStatus>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseProductStatus {
+	id: String,
+	status: String,
+	#[serde(default)]
+	trading_disabled: bool,
+	#[serde(default)]
+	cancel_only: bool,
+	#[serde(default)]
+	post_only: bool,
+}
+
+impl CoinbaseProductStatus {
+	/// Whether a taker order could go through right now. Post-only and
+	/// cancel-only both rule that out even while the product is "online".
+	fn tradable(&self) -> bool {
+		self.status == "online" && !self.trading_disabled && !self.cancel_only && !self.post_only
+	}
+
+	fn describe(&self) -> String {
+		let mut flags = vec![self.status.clone()];
+		if self.trading_disabled {
+			flags.push("trading disabled".to_string());
+		}
+		if self.cancel_only {
+			flags.push("cancel only".to_string());
+		}
+		if self.post_only {
+			flags.push("post only".to_string());
+		}
+		flags.join(", ")
+	}
+}
+
+/// Follows the status channel for the watched products to notice when one
+/// stops or starts being tradable mid-session.
+pub struct ProductStatus {
+	tradable: HashMap<String, bool>,
+}
+
+impl ProductStatus {
+	/// Products start out tradable, as they were online when configured.
+	pub fn new(products: &BTreeSet<ProductId>) -> ProductStatus {
+		ProductStatus {
+			tradable: products.iter().map(|product| (product.to_string(), true)).collect(),
+		}
+	}
+
+	/// Applies a status update, returning (product id, tradable, status) for
+	/// every watched product whose tradability changed.
+	pub fn on_status(&mut self, status: &CoinbaseStatus) -> Vec<(String, bool, String)> {
+		let mut changes = Vec::new();
+
+		for product in &status.products {
+			let tradable = match self.tradable.get_mut(&product.id) {
+				Some(tradable) => tradable,
+				None => continue,
+			};
+
+			let now_tradable = product.tradable();
+			if now_tradable != *tradable {
+				*tradable = now_tradable;
+				changes.push((product.id.clone(), now_tradable, product.describe()));
+			}
+		}
+
+		changes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+	use crate::subscription;
+
+	fn statuses(products: &[&str]) -> ProductStatus {
+		ProductStatus::new(&subscription::product_set(products).unwrap())
+	}
+
+	#[test]
+	fn a_flip_to_post_only_and_back_is_reported() {
+		let mut statuses = statuses(&["ETH-USD"]);
+
+		let changes: Vec<Vec<(String, bool, String)>> = fixtures::frames("status").iter()
+			.map(|frame| statuses.on_status(&serde_json::from_str(&frame.text).unwrap()))
+			.collect();
+
+		assert_eq!(changes, [
+			vec![],
+			vec![("ETH-USD".to_string(), false, "online, post only".to_string())],
+			vec![("ETH-USD".to_string(), true, "online".to_string())],
+		]);
+	}
+
+	#[test]
+	fn unwatched_products_are_ignored() {
+		let mut statuses = statuses(&["BTC-USD"]);
+
+		for frame in fixtures::frames("status") {
+			assert!(statuses.on_status(&serde_json::from_str(&frame.text).unwrap()).is_empty());
+		}
+	}
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::sanitize::sanitize;
+
+const MAX_CURRENCY_LEN: usize = 10;
+const CONFIRMATION_TIMEOUT_SECS: i64 = 10;
+
+/// A Coinbase product id such as "ETH-USD", checked to be two upper-case
+/// alphanumeric currency codes separated by a single dash.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProductId(String);
+
+impl ProductId {
+	pub fn parse(id: &str) -> Option<ProductId> {
+		let (base, quote) = id.split_once('-')?;
+
+		if is_currency(base) && is_currency(quote) {
+			Some(ProductId(id.to_string()))
+		} else {
+			None
+		}
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Display for ProductId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+fn is_currency(code: &str) -> bool {
+	!code.is_empty()
+		&& code.len() <= MAX_CURRENCY_LEN
+		&& code.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
+/// Validates and deduplicates a list of product ids.
+///
+/// On failure the error lists every offending id, sanitized for printing.
+pub fn product_set(ids: &[&str]) -> Result<BTreeSet<ProductId>, Vec<String>> {
+	let mut products = BTreeSet::new();
+	let mut invalid = Vec::new();
+
+	for id in ids {
+		match ProductId::parse(id) {
+			Some(product) => {
+				products.insert(product);
+			}
+			None => invalid.push(sanitize(id)),
+		}
+	}
+
+	if invalid.is_empty() {
+		Ok(products)
+	} else {
+		Err(invalid)
+	}
+}
+
+/// Builds the subscribe frame. Product ids come out sorted so the frame is
+/// the same for the same set of products.
+pub fn subscribe_message(products: &BTreeSet<ProductId>, channels: &[&str]) -> String {
+	message("subscribe", products, channels)
+}
+
+/// Builds the frame that undoes `subscribe_message` for the same arguments.
+pub fn unsubscribe_message(products: &BTreeSet<ProductId>, channels: &[&str]) -> String {
+	message("unsubscribe", products, channels)
+}
+
+fn message(message_type: &str, products: &BTreeSet<ProductId>, channels: &[&str]) -> String {
+	let product_ids: Vec<&str> = products.iter().map(ProductId::as_str).collect();
+
+	serde_json::json!({
+		"type": message_type,
+		"product_ids": product_ids,
+		"channels": channels,
+	}).to_string()
+}
+
+/// Compares the subscriptions Coinbase confirms with the products we asked
+/// for, so a product it silently left out isn't mistaken for a quiet one.
+///
+/// Each shard's connection only confirms its own products, so nothing is
+/// judged until `CONFIRMATION_TIMEOUT_SECS` after the first frame, by which
+/// time every shard should have answered.
+pub struct Confirmations {
+	requested: BTreeSet<ProductId>,
+	confirmed: BTreeMap<String, BTreeSet<String>>,
+	first_frame: Option<DateTime<Utc>>,
+	checked: bool,
+}
+
+impl Confirmations {
+	pub fn new(products: &BTreeSet<ProductId>) -> Confirmations {
+		Confirmations {
+			requested: products.clone(),
+			confirmed: BTreeMap::new(),
+			first_frame: None,
+			checked: false,
+		}
+	}
+
+	/// Records a channel's confirmed products. Channels confirmed without
+	/// product ids, like status, cover every product and aren't checked.
+	pub fn on_confirmed(&mut self, channel: &str, product_ids: &[String]) {
+		if product_ids.is_empty() {
+			return;
+		}
+
+		self.confirmed.entry(channel.to_string())
+			.or_default()
+			.extend(product_ids.iter().cloned());
+	}
+
+	/// The products confirmed so far on `channel`, if it was confirmed.
+	#[cfg(test)]
+	pub fn confirmed(&self, channel: &str) -> Option<&BTreeSet<String>> {
+		self.confirmed.get(channel)
+	}
+
+	/// Once the timeout has passed, returns a warning, a single time, for
+	/// every channel some requested product is missing from.
+	pub fn check(&mut self, received: DateTime<Utc>) -> Vec<String> {
+		if self.checked {
+			return Vec::new();
+		}
+
+		let first_frame = *self.first_frame.get_or_insert(received);
+		if received.signed_duration_since(first_frame).num_seconds() < CONFIRMATION_TIMEOUT_SECS {
+			return Vec::new();
+		}
+		self.checked = true;
+
+		if self.confirmed.is_empty() {
+			return vec![format!("no subscriptions confirmed after {}s", CONFIRMATION_TIMEOUT_SECS)];
+		}
+
+		let mut warnings = Vec::new();
+		for (channel, confirmed) in &self.confirmed {
+			let missing: Vec<&str> = self.requested.iter()
+				.map(ProductId::as_str)
+				.filter(|product_id| !confirmed.contains(*product_id))
+				.collect();
+
+			if !missing.is_empty() {
+				warnings.push(format!("{} channel not confirmed for {}", sanitize(channel), missing.join(", ")));
+			}
+		}
+		warnings
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+	use chrono::Duration;
+
+	#[test]
+	fn product_ids_must_be_two_upper_case_codes() {
+		assert!(ProductId::parse("ETH-USD").is_some());
+		assert!(ProductId::parse("1INCH-USDT").is_some());
+
+		for id in ["eth-usd", "ETHUSD", "ETH-", "-USD", "ETH-USD-X", "ETH_USD", "ETH-ABCDEFGHIJK", "ETH-US D"] {
+			assert!(ProductId::parse(id).is_none(), "{} was accepted", id);
+		}
+	}
+
+	#[test]
+	fn product_set_deduplicates_and_sorts() {
+		let products = product_set(&["ETH-USD", "BTC-USD", "ETH-USD"]).unwrap();
+		let ids: Vec<&str> = products.iter().map(ProductId::as_str).collect();
+
+		assert_eq!(ids, ["BTC-USD", "ETH-USD"]);
+	}
+
+	#[test]
+	fn product_set_names_every_invalid_id() {
+		let invalid = product_set(&["ETH-USD", "btc-usd", "SOLUSD", "\u{1b}[31m-USD"]).unwrap_err();
+
+		assert_eq!(invalid, ["btc-usd", "SOLUSD", "\\u{1b}[31m-USD"]);
+	}
+
+	#[test]
+	fn subscribe_message_is_the_same_for_the_same_products() {
+		let a = product_set(&["ETH-USD", "BTC-USD"]).unwrap();
+		let b = product_set(&["BTC-USD", "ETH-USD", "BTC-USD"]).unwrap();
+
+		assert_eq!(subscribe_message(&a, &["full"]), subscribe_message(&b, &["full"]));
+	}
+
+	#[test]
+	fn a_product_left_out_of_a_confirmation_is_warned_about_once() {
+		let mut confirmations = Confirmations::new(&product_set(&["ETH-USD", "BTC-USD"]).unwrap());
+
+		// The control fixture confirms ETH-USD alone.
+		let frame = fixtures::frames("control").remove(0);
+		let subscriptions: serde_json::Value = serde_json::from_str(&frame.text).unwrap();
+		for channel in subscriptions["channels"].as_array().unwrap() {
+			let product_ids: Vec<String> = serde_json::from_value(channel["product_ids"].clone()).unwrap();
+			confirmations.on_confirmed(channel["name"].as_str().unwrap(), &product_ids);
+		}
+
+		let timeout = Duration::seconds(CONFIRMATION_TIMEOUT_SECS);
+		assert!(confirmations.check(frame.received).is_empty());
+		assert_eq!(confirmations.check(frame.received + timeout), [
+			"full channel not confirmed for BTC-USD",
+			"heartbeat channel not confirmed for BTC-USD",
+		]);
+		assert!(confirmations.check(frame.received + timeout * 2).is_empty());
+
+		let eth_usd = BTreeSet::from(["ETH-USD".to_string()]);
+		assert_eq!(confirmations.confirmed("full"), Some(&eth_usd));
+		assert_eq!(confirmations.confirmed("heartbeat"), Some(&eth_usd));
+		assert_eq!(confirmations.confirmed("status"), None);
+	}
+}
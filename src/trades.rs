@@ -0,0 +1,86 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+
+const WINDOW_SECONDS: i64 = 60;
+
+struct ProductTrades {
+	recent: VecDeque<(DateTime<Utc>, f64)>,
+	last_price: f64,
+}
+
+/// Rolling executed volume and last trade price per product.
+pub struct TradeFlow {
+	products: HashMap<String, ProductTrades>,
+}
+
+impl TradeFlow {
+	pub fn new() -> TradeFlow {
+		TradeFlow {
+			products: HashMap::new(),
+		}
+	}
+
+	pub fn on_trade(&mut self, product_id: &str, time: DateTime<Utc>, price: f64, size: f64) {
+		let product = self.products.entry(product_id.to_string())
+			.or_insert_with(|| ProductTrades {
+				recent: VecDeque::new(),
+				last_price: price,
+			});
+
+		product.last_price = price;
+		product.recent.push_back((time, size));
+
+		let cutoff = time - Duration::seconds(WINDOW_SECONDS);
+		while product.recent.front().is_some_and(|(time, _)| *time < cutoff) {
+			product.recent.pop_front();
+		}
+	}
+
+	/// Units traded in the minute up to `now` and the last trade price, or
+	/// None if no trade has been seen for the product.
+	///
+	/// `now` is the receive time of the current frame rather than the wall
+	/// clock, so a replay reports the same volumes the live session saw.
+	pub fn recent(&self, product_id: &str, now: DateTime<Utc>) -> Option<(f64, f64)> {
+		let product = self.products.get(product_id)?;
+		let cutoff = now - Duration::seconds(WINDOW_SECONDS);
+		let volume = product.recent.iter()
+			.filter(|(time, _)| *time >= cutoff)
+			.map(|(_, size)| size)
+			.sum();
+
+		Some((volume, product.last_price))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn at(seconds: i64) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339("2026-10-12T14:03:00Z").unwrap().with_timezone(&Utc) + Duration::seconds(seconds)
+	}
+
+	#[test]
+	fn volume_covers_the_last_minute() {
+		let mut trades = TradeFlow::new();
+
+		trades.on_trade("ETH-USD", at(0), 2450.11, 0.25);
+		trades.on_trade("ETH-USD", at(30), 2450.10, 0.5);
+		trades.on_trade("ETH-USD", at(61), 2450.12, 1.5);
+
+		assert_eq!(trades.recent("ETH-USD", at(61)), Some((2.0, 2450.12)));
+		assert_eq!(trades.recent("ETH-USD", at(100)), Some((1.5, 2450.12)));
+	}
+
+	#[test]
+	fn products_are_tracked_separately() {
+		let mut trades = TradeFlow::new();
+
+		trades.on_trade("ETH-USD", at(0), 2450.11, 0.25);
+
+		assert_eq!(trades.recent("BTC-USD", at(0)), None);
+		assert_eq!(trades.recent("ETH-USD", at(0)), Some((0.25, 2450.11)));
+	}
+}